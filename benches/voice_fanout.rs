@@ -0,0 +1,62 @@
+//! Cost of sending one voice packet to many recipients: the previous path encoded the packet for
+//! each recipient before encrypting it, the shared path encodes it once per voice protocol.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::marker::PhantomData;
+use zumble::crypt::CryptState;
+use zumble::voice::{ClientBound, SharedVoicePacket, VoicePacket, VoicePacketPayload, VoiceProtocol};
+
+const RECIPIENTS: [usize; 3] = [1, 32, 256];
+
+fn audio_packet() -> VoicePacket<ClientBound> {
+    VoicePacket::Audio {
+        _dst: PhantomData,
+        target: 0,
+        session_id: 42,
+        seq_num: 1234,
+        // a typical 20ms opus frame
+        payload: VoicePacketPayload::Opus(Bytes::from(vec![0x5a; 80]), false),
+        position_info: Some(Bytes::from(vec![0; 12])),
+        volume_adjustment: None,
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let packet = audio_packet();
+
+    for protocol in [VoiceProtocol::Legacy, VoiceProtocol::Protobuf] {
+        let mut group = c.benchmark_group(format!("voice_fanout/{:?}", protocol));
+
+        for recipients in RECIPIENTS {
+            let mut crypt_states: Vec<CryptState> = (0..recipients).map(|_| CryptState::default()).collect();
+
+            group.throughput(Throughput::Elements(recipients as u64));
+
+            group.bench_with_input(BenchmarkId::new("encode_per_recipient", recipients), &packet, |b, packet| {
+                b.iter(|| {
+                    for crypt_state in crypt_states.iter_mut() {
+                        let mut dst = BytesMut::new();
+                        crypt_state.encrypt(protocol, packet, &mut dst).unwrap();
+                    }
+                })
+            });
+
+            group.bench_with_input(BenchmarkId::new("shared_encoding", recipients), &packet, |b, packet| {
+                b.iter(|| {
+                    let shared = SharedVoicePacket::new(packet.clone());
+
+                    for crypt_state in crypt_states.iter_mut() {
+                        let mut dst = BytesMut::new();
+                        crypt_state.encrypt_bytes(shared.encoded(protocol).unwrap(), &mut dst);
+                    }
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
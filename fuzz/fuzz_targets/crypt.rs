@@ -0,0 +1,52 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use zumble::crypt::CryptState;
+use zumble::voice::{ServerBound, VoiceProtocol};
+
+const KEY: [u8; 16] = [0x42; 16];
+const CLIENT_NONCE: [u8; 16] = [0x01; 16];
+const SERVER_NONCE: [u8; 16] = [0x02; 16];
+
+// The input is a sequence of operations on the crypt state of a client, each one is a byte selecting
+// the operation, a byte for the length and the data:
+// - raw datagrams decrypted as is, exercising the header and nonce window logic
+// - payloads encrypted by the client, after skipping some nonces to reorder or lose packets, so the
+//   decrypted voice packet decoder sees them too
+fuzz_target!(|data: &[u8]| {
+    let mut server = CryptState::default();
+    server.set_key(KEY, SERVER_NONCE, CLIENT_NONCE);
+
+    let mut client = CryptState::default();
+    client.set_key(KEY, CLIENT_NONCE, SERVER_NONCE);
+
+    let mut data = data;
+
+    while data.len() >= 2 {
+        let (op, len) = (data[0], data[1] as usize);
+        let payload = &data[2..(2 + len).min(data.len())];
+        data = &data[2 + payload.len()..];
+
+        let protocol = if op & 0x02 == 0 {
+            VoiceProtocol::Legacy
+        } else {
+            VoiceProtocol::Protobuf
+        };
+
+        let mut datagram = if op & 0x01 == 0 {
+            BytesMut::from(payload)
+        } else {
+            let mut datagram = BytesMut::new();
+
+            for _ in 0..(op >> 2) {
+                client.encrypt_bytes(&[], &mut BytesMut::new());
+            }
+
+            client.encrypt_bytes(payload, &mut datagram);
+            datagram
+        };
+
+        let _ = server.decrypt::<ServerBound>(protocol, &mut datagram);
+    }
+});
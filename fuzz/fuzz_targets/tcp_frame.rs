@@ -0,0 +1,44 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use protobuf::Message;
+use zumble::config::TcpConfig;
+use zumble::proto::{mumble, read_frame, MessageKind};
+use zumble::voice::{ServerBound, VoiceProtocol};
+
+fn parse<T: Message>(payload: &[u8]) {
+    let _ = T::parse_from_bytes(payload);
+}
+
+// Reads the frames of a tcp stream like the message handler, and parses the payload of every kind it
+// handles
+fuzz_target!(|data: &[u8]| {
+    let limits = TcpConfig::default();
+    let mut stream = data;
+
+    futures::executor::block_on(async {
+        while let Ok((kind, payload)) = read_frame(&mut stream, &limits).await {
+            match kind {
+                MessageKind::Version => parse::<mumble::Version>(&payload),
+                MessageKind::UDPTunnel => {
+                    let _ = VoiceProtocol::Legacy.decode::<ServerBound>(&mut BytesMut::clone(&payload));
+                    let _ = VoiceProtocol::Protobuf.decode::<ServerBound>(&mut BytesMut::clone(&payload));
+                }
+                MessageKind::Authenticate => parse::<mumble::Authenticate>(&payload),
+                MessageKind::Ping => parse::<mumble::Ping>(&payload),
+                MessageKind::ChannelState => parse::<mumble::ChannelState>(&payload),
+                MessageKind::CryptSetup => {
+                    if let Ok(crypt_setup) = mumble::CryptSetup::parse_from_bytes(&payload) {
+                        let _ = zumble::crypt::CryptState::default().set_decrypt_nonce(crypt_setup.get_client_nonce());
+                    }
+                }
+                MessageKind::PermissionQuery => parse::<mumble::PermissionQuery>(&payload),
+                MessageKind::UserState => parse::<mumble::UserState>(&payload),
+                MessageKind::VoiceTarget => parse::<mumble::VoiceTarget>(&payload),
+                MessageKind::UserStats => parse::<mumble::UserStats>(&payload),
+                _ => (),
+            }
+        }
+    });
+});
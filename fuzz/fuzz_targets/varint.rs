@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use zumble::varint::{ReadExt, WriteExt};
+
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+
+    if let Ok(value) = cursor.read_varint() {
+        // values may have several encodings, the one of the writer must decode to the same value
+        let mut encoded = Vec::new();
+        encoded.write_varint(value).unwrap();
+
+        assert_eq!(Cursor::new(&encoded).read_varint().unwrap(), value);
+    }
+});
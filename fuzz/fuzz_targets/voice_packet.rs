@@ -0,0 +1,34 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use zumble::voice::{ClientBound, ServerBound, VoicePacket, VoicePacketDst, VoiceProtocol};
+
+fn check<Dst: VoicePacketDst>(protocol: VoiceProtocol, data: &[u8]) {
+    let Ok(packet) = protocol.decode::<Dst>(&mut BytesMut::from(data)) else {
+        return;
+    };
+
+    // decoded packets are forwarded to other clients, possibly using the other format
+    for protocol in [VoiceProtocol::Legacy, VoiceProtocol::Protobuf] {
+        let mut encoded = BytesMut::new();
+        if protocol.encode(&packet, &mut encoded).is_ok() {
+            let _ = protocol.decode::<Dst>(&mut encoded);
+        }
+    }
+
+    if let VoicePacket::Audio {
+        position_info: Some(position_info),
+        ..
+    } = &packet
+    {
+        let _ = zumble::voice::read_position(position_info);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check::<ServerBound>(VoiceProtocol::Legacy, data);
+    check::<ClientBound>(VoiceProtocol::Legacy, data);
+    check::<ServerBound>(VoiceProtocol::Protobuf, data);
+    check::<ClientBound>(VoiceProtocol::Protobuf, data);
+});
@@ -2,6 +2,7 @@ use scc::HashMap;
 
 use crate::client::ClientRef;
 use crate::proto::mumble::ChannelState;
+use crate::recipients::Recipients;
 use std::sync::Arc;
 
 pub type ChannelRef = Arc<Channel>;
@@ -15,6 +16,13 @@ pub struct Channel {
     pub temporary: bool,
     pub listeners: HashMap<u32, ClientRef>,
     pub clients: HashMap<u32, ClientRef>,
+    /// Clients of the channel which can hear, receiving the channel audio
+    pub recipients: Recipients,
+    /// Clients and listeners of the channel which can hear, receiving the audio of the voice targets
+    /// including this channel
+    pub target_recipients: Recipients,
+    /// Voice targets (session id of the owner and target id) including this channel
+    pub targeted_by: HashMap<(u32, u8), ()>,
     channel_state_cache: Arc<ChannelState>,
 }
 
@@ -42,6 +50,9 @@ impl Channel {
             temporary,
             clients: HashMap::new(),
             listeners: HashMap::new(),
+            recipients: Recipients::default(),
+            target_recipients: Recipients::default(),
+            targeted_by: HashMap::new(),
         })
     }
 
@@ -56,4 +67,33 @@ impl Channel {
     pub fn get_clients(&self) -> &HashMap<u32, ClientRef> {
         &self.clients
     }
+
+    /// Rebuilds the recipients of the channel, voice targets including it must be refreshed by the
+    /// caller
+    pub async fn refresh_recipients(&self) {
+        let generation = self.recipients.start_refresh();
+        let target_generation = self.target_recipients.start_refresh();
+        let mut recipients = Vec::with_capacity(self.clients.len());
+
+        self.clients
+            .scan_async(|_, client| {
+                if client.can_hear() {
+                    recipients.push(Arc::clone(client));
+                }
+            })
+            .await;
+
+        let mut target_recipients = recipients.clone();
+
+        self.listeners
+            .scan_async(|session_id, client| {
+                if client.can_hear() && !self.clients.contains(session_id) {
+                    target_recipients.push(Arc::clone(client));
+                }
+            })
+            .await;
+
+        self.recipients.set(generation, recipients);
+        self.target_recipients.set(target_generation, target_recipients);
+    }
 }
@@ -1,5 +1,8 @@
+use crate::crypt::ResyncCause;
 use crate::error::MumbleError;
 use crate::state::{ServerState, ServerStateRef};
+use crate::udp_health::Transport;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -21,6 +24,7 @@ pub async fn clean_loop(state: ServerStateRef) {
 async fn clean_run(state: &ServerState) -> Result<(), MumbleError> {
     let mut clients_to_remove = Vec::new();
     let mut clients_to_reset_crypt = Vec::new();
+    let mut transports = [(Transport::Tcp, 0), (Transport::Udp, 0), (Transport::Fallback, 0)];
 
     {
         let mut iter = state.clients.first_entry_async().await;
@@ -33,6 +37,42 @@ async fn clean_run(state: &ServerState) -> Result<(), MumbleError> {
                 clients_to_remove.push(client.session_id);
             }
 
+            // the client can't keep up with its messages, its queue would grow without limit
+            let backlog = client.publisher.backlog();
+
+            if backlog.as_millis() > state.config.client_queue.max_backlog_ms as u128 {
+                tracing::warn!(
+                    "disconnecting slow consumer {}, oldest queued message is {:?} old",
+                    client.get(),
+                    backlog
+                );
+
+                crate::metrics::SLOW_CONSUMER_DISCONNECTS_TOTAL.inc();
+                can_reset_crypt = false;
+                clients_to_remove.push(client.session_id);
+            }
+
+            let (control_depth, voice_depth) = client.publisher.depth();
+            let session = client.session_id.to_string();
+
+            crate::metrics::CLIENT_QUEUE_DEPTH
+                .with_label_values(&[&session, "control"])
+                .set(control_depth as i64);
+            crate::metrics::CLIENT_QUEUE_DEPTH
+                .with_label_values(&[&session, "voice"])
+                .set(voice_depth as i64);
+            crate::metrics::CLIENT_QUEUE_DROPPED
+                .with_label_values(&[&session])
+                .set(client.publisher.voice_dropped.load(Ordering::Relaxed) as i64);
+
+            let transport = client.get_transport();
+
+            for (kind, count) in transports.iter_mut() {
+                if *kind == transport {
+                    *count += 1;
+                }
+            }
+
             let now = Instant::now();
 
             let duration = now.duration_since(client.last_ping.load());
@@ -42,11 +82,15 @@ async fn clean_run(state: &ServerState) -> Result<(), MumbleError> {
                 clients_to_remove.push(client.session_id);
             }
 
-            if can_reset_crypt {
-                let last_good = { client.crypt_state.lock().await.last_good };
+            // only the crypt state of clients using udp can be broken, they keep sending udp pings
+            // even when silent so missing pings means their packets can't be decrypted anymore
+            if can_reset_crypt && client.udp_socket_addr.load().is_some() {
+                let udp_ping_age = now.duration_since(client.last_udp_ping.load());
 
-                if now.duration_since(last_good).as_millis() > 8000 {
+                if udp_ping_age.as_millis() > state.config.crypt.udp_ping_timeout_ms as u128 {
                     clients_to_reset_crypt.push(Arc::clone(client.get()))
+                } else {
+                    client.resync_backoff.lock().reset();
                 }
             }
 
@@ -54,12 +98,16 @@ async fn clean_run(state: &ServerState) -> Result<(), MumbleError> {
         }
     }
 
+    for (transport, count) in transports {
+        crate::metrics::CLIENTS_BY_TRANSPORT
+            .with_label_values(&[transport.as_str()])
+            .set(count);
+    }
+
     for client in clients_to_reset_crypt {
         let session_id = client.session_id;
-        if let Err(e) = state.reset_client_crypt(&client).await {
+        if let Err(e) = state.resync_client_crypt(&client, ResyncCause::Timeout).await {
             tracing::error!("failed to send crypt setup for {}: {:?}", e, session_id);
-        } else {
-            tracing::info!("Requesting {} crypt be reset", client);
         }
     }
 
@@ -1,34 +1,44 @@
-use crate::crypt::CryptState;
+use crate::config::{Config, TcpConfig};
+use crate::crypt::{CryptState, ResyncBackoff, ResyncCause};
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
-use crate::proto::mumble::{Authenticate, ServerConfig, ServerSync, UDPTunnel, UserState, Version};
-use crate::proto::{expected_message, message_to_bytes, send_message, MessageKind};
-use crate::server::constants::MAX_BANDWIDTH_IN_BITS;
+use crate::message::ClientQueue;
+use crate::proto::mumble::{Authenticate, CryptSetup, ServerConfig, ServerSync, UDPTunnel, UserState, Version};
+use crate::proto::{get_version_v2, message_to_bytes, read_frame, send_message, MessageKind};
+use crate::server::{HandshakeStage, TcpWriter, UdpWriter};
 use crate::state::ServerStateRef;
 use crate::target::VoiceTarget;
-use crate::voice::{encode_voice_packet, ClientBound, VoicePacket};
+use crate::udp_health::{Transport, UdpHealth};
+use crate::voice::{SharedVoicePacket, VoiceProtocol};
 use arc_swap::ArcSwapOption;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use crossbeam::atomic::AtomicCell;
 use protobuf::Message;
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncWriteExt, WriteHalf};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
-use tokio::time::timeout;
+use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
 pub type ClientRef = Arc<Client>;
 
 type VoiceTargetArray = [Arc<VoiceTarget>; 29];
 
+/// What was negotiated with the client while it connected, used to create the [Client]
+pub struct ClientConnection {
+    pub version: Version,
+    pub voice_protocol: VoiceProtocol,
+    pub authenticate: Authenticate,
+    pub crypt_state: CryptState,
+    pub writer: TcpWriter,
+    pub peer_ip: IpAddr,
+}
+
 pub struct Client {
-    // pub version: Version,
+    /// Version sent by the client, empty for clients which authenticated without sending it
+    pub version: Version,
     name: Arc<String>,
     pub log_name: Arc<String>,
     pub authenticate: Authenticate,
@@ -36,16 +46,141 @@ pub struct Client {
     pub channel_id: AtomicU32,
     pub mute: AtomicBool,
     pub deaf: AtomicBool,
-    pub write: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pub suppress: AtomicBool,
+    pub self_mute: AtomicBool,
+    pub self_deaf: AtomicBool,
+    pub recording: AtomicBool,
+    pub priority_speaker: AtomicBool,
+    pub details: parking_lot::Mutex<UserDetails>,
+    /// Until when the audio of other speakers is ducked or suppressed for this client, because a
+    /// priority speaker is talking to it
+    pub priority_hold_until: AtomicCell<Instant>,
+    pub writer: TcpWriter,
     // pub tokens: Vec<String>,
-    pub crypt_state: Mutex<CryptState>,
+    pub crypt_state: parking_lot::Mutex<CryptState>,
     pub udp_socket_addr: ArcSwapOption<SocketAddr>,
     // pub use_opus: bool,
     pub codecs: Vec<i32>,
-    pub udp_socket: Arc<UdpSocket>,
-    pub publisher: Sender<ClientMessage>,
+    pub voice_protocol: VoiceProtocol,
+    pub udp_writer: UdpWriter,
+    /// Outbound messages and voice packets, consumed by the client task
+    pub publisher: ClientQueue,
     pub targets: VoiceTargetArray,
+    /// Voice targets of other clients (session id of the owner and target id) including this client
+    pub targeted_by: scc::HashMap<(u32, u8), ()>,
     pub last_ping: AtomicCell<Instant>,
+    /// When the client last sent an udp ping which could be decrypted
+    pub last_udp_ping: AtomicCell<Instant>,
+    pub resync_backoff: parking_lot::Mutex<ResyncBackoff>,
+    pub udp_health: UdpHealth,
+    /// Last known position of the client, from the positional audio information of its voice
+    /// packets or pushed by the game server
+    pub position: AtomicCell<Option<[f32; 3]>>,
+    /// Routing bucket (instance) of the client in the game, only clients in the same bucket can hear
+    /// each other
+    pub bucket: AtomicU32,
+    pub peer_ip: IpAddr,
+    pub connected_at: Instant,
+    /// When the client last sent a voice packet
+    pub last_voice: AtomicCell<Instant>,
+    /// Connection statistics reported by the client in its pings
+    pub ping_stats: parking_lot::Mutex<PingStats>,
+    /// Resynchronizations of the crypt state by cause (indexed like `ResyncCause::ALL`), they are
+    /// kept when the crypt state is reset
+    pub resyncs: [AtomicU32; 4],
+    bandwidth: parking_lot::Mutex<BandwidthRecorder>,
+}
+
+/// Connection statistics reported by the client in its pings: the packets it received from the
+/// server and its own round trip time measurements, along with the rolling round trip statistics
+/// derived from them
+#[derive(Default, Clone, Copy)]
+pub struct PingStats {
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+    pub udp_packets: u32,
+    pub tcp_packets: u32,
+    pub udp_ping_avg: f32,
+    pub udp_ping_var: f32,
+    pub tcp_ping_avg: f32,
+    pub tcp_ping_var: f32,
+    pub tcp_rtt: RttStats,
+    pub udp_rtt: RttStats,
+}
+
+/// Rolling round trip time and jitter of one transport, in milliseconds.
+///
+/// The clients echo the pings of the server instead of answering them, so the server can't time
+/// them itself. The clients report the mean and the count of all their round trip times since they
+/// connected, the mean of the pings since the previous report is recovered from two reports and
+/// smoothed like the tcp retransmission timer (RFC 6298).
+#[derive(Default, Clone, Copy)]
+pub struct RttStats {
+    pub rtt: f32,
+    pub jitter: f32,
+    pub samples: u32,
+    reported_avg: f32,
+    reported_count: u32,
+}
+
+impl RttStats {
+    /// Updates the statistics from the mean and count reported by the client, returns the new
+    /// sample if there were pings since the previous report
+    pub fn record(&mut self, avg: f32, count: u32) -> Option<f32> {
+        let sample = match count.checked_sub(self.reported_count) {
+            Some(0) => None,
+            Some(new) => Some((avg * count as f32 - self.reported_avg * self.reported_count as f32) / new as f32),
+            // the client reset its statistics, after a reconnection of its udp for example
+            None => Some(avg),
+        };
+
+        self.reported_avg = avg;
+        self.reported_count = count;
+
+        let sample = sample.filter(|sample| sample.is_finite())?.max(0.0);
+
+        if self.samples == 0 {
+            self.rtt = sample;
+            self.jitter = sample / 2.0;
+        } else {
+            self.jitter += ((self.rtt - sample).abs() - self.jitter) / 4.0;
+            self.rtt += (sample - self.rtt) / 8.0;
+        }
+
+        self.samples += 1;
+
+        Some(sample)
+    }
+}
+
+/// Voice bytes sent by the client during the current and the previous second
+struct BandwidthRecorder {
+    second_start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl BandwidthRecorder {
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.second_start).as_secs();
+
+        if elapsed >= 1 {
+            self.previous = if elapsed == 1 { self.current } else { 0 };
+            self.current = 0;
+            self.second_start += Duration::from_secs(elapsed);
+        }
+    }
+}
+
+/// User state fields which are only stored and synced to the other clients, they are not used to
+/// route the audio
+#[derive(Default)]
+pub struct UserDetails {
+    pub comment: String,
+    pub plugin_context: Vec<u8>,
+    pub plugin_identity: String,
 }
 
 impl Display for Client {
@@ -58,14 +193,37 @@ impl Client {
     pub async fn init(
         stream: &mut TlsStream<TcpStream>,
         server_version: Version,
+        limits: &TcpConfig,
     ) -> Result<(Version, Authenticate, CryptState), MumbleError> {
-        let version: Version = expected_message(MessageKind::Version, stream, 0).await?;
-
-        // Send version
+        // like murmur the server version is sent first, the client may then authenticate without
+        // sending its own version
         send_message(MessageKind::Version, &server_version, stream).await?;
 
-        // Get authenticate
-        let authenticate: Authenticate = expected_message(MessageKind::Authenticate, stream, 0).await?;
+        let mut version = None;
+        let mut stage = HandshakeStage::Version;
+        let mut deadline = stage.deadline(limits);
+
+        let authenticate = loop {
+            let (kind, payload) = stage.run_until(deadline, read_frame(stream, limits)).await??;
+
+            match kind {
+                MessageKind::Version => {
+                    version = Some(Version::parse_from_bytes(&payload)?);
+
+                    if stage == HandshakeStage::Version {
+                        stage = HandshakeStage::Authenticate;
+                        deadline = stage.deadline(limits);
+                    }
+                }
+                MessageKind::Authenticate => break Authenticate::parse_from_bytes(&payload)?,
+                // like murmur, the other messages (pings, tunneled voice...) are ignored until the
+                // client is authenticated
+                _ => tracing::trace!("ignoring {} message received during the {} stage", kind, stage),
+            }
+        };
+
+        // clients which never sent their version are considered legacy ones
+        let version = version.unwrap_or_default();
 
         let crypt = CryptState::default();
         let crypt_setup = crypt.get_crypt_setup();
@@ -73,43 +231,65 @@ impl Client {
         // Send crypt setup
         send_message(MessageKind::CryptSetup, &crypt_setup, stream).await?;
 
-
         Ok((version, authenticate, crypt))
     }
 
-    pub fn new(
-        _version: Version,
-        authenticate: Authenticate,
-        session_id: u32,
-        channel_id: u32,
-        crypt_state: CryptState,
-        write: WriteHalf<TlsStream<TcpStream>>,
-        udp_socket: Arc<UdpSocket>,
-        publisher: Sender<ClientMessage>,
-    ) -> Arc<Self> {
+    pub fn new(connection: ClientConnection, session_id: u32, channel_id: u32, udp_writer: UdpWriter, publisher: ClientQueue) -> Arc<Self> {
+        let ClientConnection {
+            version,
+            voice_protocol,
+            authenticate,
+            crypt_state,
+            writer,
+            peer_ip,
+        } = connection;
         // let tokens = authenticate.get_tokens().iter().map(|token| token.to_string()).collect();
         let targets: VoiceTargetArray = core::array::from_fn(|_v| Arc::new(VoiceTarget::default()));
 
         Arc::new(Self {
-            // version,
+            version,
             session_id,
             log_name: Arc::new(format!("{} [session id: {}]", authenticate.get_username(), session_id)),
 
             name: Arc::new(authenticate.get_username().to_string()),
             channel_id: AtomicU32::new(channel_id),
-            crypt_state: Mutex::new(crypt_state),
-            write: Mutex::new(write),
+            crypt_state: parking_lot::Mutex::new(crypt_state),
+            writer,
             // tokens,
             deaf: AtomicBool::new(false),
             mute: AtomicBool::new(false),
+            suppress: AtomicBool::new(false),
+            self_mute: AtomicBool::new(false),
+            self_deaf: AtomicBool::new(false),
+            recording: AtomicBool::new(false),
+            priority_speaker: AtomicBool::new(false),
+            details: parking_lot::Mutex::new(UserDetails::default()),
+            priority_hold_until: AtomicCell::new(Instant::now()),
             udp_socket_addr: ArcSwapOption::from(None),
             // use_opus: if authenticate.has_opus() { authenticate.get_opus() } else { false },
             codecs: authenticate.get_celt_versions().to_vec(),
+            voice_protocol,
             authenticate,
-            udp_socket,
+            udp_writer,
             publisher,
             targets,
+            targeted_by: scc::HashMap::new(),
             last_ping: AtomicCell::new(Instant::now()),
+            last_udp_ping: AtomicCell::new(Instant::now()),
+            resync_backoff: parking_lot::Mutex::new(ResyncBackoff::default()),
+            udp_health: UdpHealth::default(),
+            position: AtomicCell::new(None),
+            bucket: AtomicU32::new(0),
+            peer_ip,
+            connected_at: Instant::now(),
+            last_voice: AtomicCell::new(Instant::now()),
+            ping_stats: parking_lot::Mutex::new(PingStats::default()),
+            resyncs: Default::default(),
+            bandwidth: parking_lot::Mutex::new(BandwidthRecorder {
+                second_start: Instant::now(),
+                current: 0,
+                previous: 0,
+            }),
         })
     }
 
@@ -124,14 +304,57 @@ impl Client {
         &self.name
     }
 
-    pub async fn send(&self, data: &[u8]) -> Result<(), MumbleError> {
-        let mut writer = self.write.lock().await;
-        match writer.write_all(data).await {
-            Ok(_bytes) => Ok(()),
-            Err(e) => Err(e.into()),
+    /// How the voice packets reach the client
+    pub fn get_transport(&self) -> Transport {
+        if self.udp_socket_addr.load().is_none() {
+            Transport::Tcp
+        } else if self.udp_health.is_fallback() {
+            Transport::Fallback
+        } else {
+            Transport::Udp
         }
     }
 
+    /// Counts a resynchronization of the crypt state of the client
+    pub fn record_resync(&self, cause: ResyncCause) {
+        self.resyncs[cause as usize].fetch_add(1, Ordering::Relaxed);
+
+        crate::metrics::CRYPT_RESYNCS_TOTAL.with_label_values(&[cause.as_str()]).inc();
+    }
+
+    pub fn get_resyncs(&self, cause: ResyncCause) -> u32 {
+        self.resyncs[cause as usize].load(Ordering::Relaxed)
+    }
+
+    /// Records a voice packet received from the client, over udp or tunneled through tcp
+    pub fn record_voice(&self, size: usize) {
+        let now = Instant::now();
+
+        self.last_voice.store(now);
+
+        let mut bandwidth = self.bandwidth.lock();
+        bandwidth.rotate(now);
+        bandwidth.current = bandwidth.current.saturating_add(size as u32);
+    }
+
+    /// Voice bandwidth used by the client during the last second, in bytes per second
+    pub fn get_bandwidth(&self) -> u32 {
+        let mut bandwidth = self.bandwidth.lock();
+        bandwidth.rotate(Instant::now());
+
+        bandwidth.previous
+    }
+
+    /// Protocol version of the client in the `version_v2` format, 0 if it never sent its version
+    pub fn get_version_v2(&self) -> u64 {
+        get_version_v2(&self.version)
+    }
+
+    /// Queues the frame on the tcp writer of the client, without waiting
+    pub fn send(&self, frame: Bytes) -> Result<(), MumbleError> {
+        self.writer.send(frame)
+    }
+
     pub fn is_muted(&self) -> bool {
         self.mute.load(Ordering::Relaxed)
     }
@@ -148,6 +371,70 @@ impl Client {
         self.deaf.store(deaf, Ordering::Release);
     }
 
+    pub fn is_suppressed(&self) -> bool {
+        self.suppress.load(Ordering::Relaxed)
+    }
+
+    pub fn is_self_muted(&self) -> bool {
+        self.self_mute.load(Ordering::Relaxed)
+    }
+
+    pub fn is_self_deaf(&self) -> bool {
+        self.self_deaf.load(Ordering::Relaxed)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Whether the audio of the client is delivered to the other clients
+    pub fn can_speak(&self) -> bool {
+        !self.is_muted() && !self.is_suppressed() && !self.is_self_muted()
+    }
+
+    /// Whether the client receives the audio of the other clients
+    pub fn can_hear(&self) -> bool {
+        !self.is_deaf() && !self.is_self_deaf()
+    }
+
+    /// Clients authenticating with the admin password of the config can moderate the other clients
+    pub fn is_admin(&self, config: &Config) -> bool {
+        match &config.admin_password {
+            Some(password) => {
+                ring::constant_time::verify_slices_are_equal(password.as_bytes(), self.authenticate.get_password().as_bytes()).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_priority_speaker(&self) -> bool {
+        self.priority_speaker.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority_speaker(&self, priority_speaker: bool) {
+        self.priority_speaker.store(priority_speaker, Ordering::Release);
+    }
+
+    pub fn get_bucket(&self) -> u32 {
+        self.bucket.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bucket(&self, bucket: u32) {
+        self.bucket.store(bucket, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client is within the radius of the given position, clients without a
+    /// known position (they never sent positional audio) are always considered in range
+    pub fn is_in_range(&self, position: Option<[f32; 3]>, radius: f32) -> bool {
+        let (Some(position), Some(own_position)) = (position, self.position.load()) else {
+            return true;
+        };
+
+        let distance_squared: f32 = position.iter().zip(own_position.iter()).map(|(a, b)| (a - b) * (a - b)).sum();
+
+        distance_squared <= radius * radius
+    }
+
     pub async fn send_message<T: Message>(&self, kind: MessageKind, message: &T) -> Result<(), MumbleError> {
         tracing::trace!(
             "[{}] [{}] send message: {:?}, {:?}",
@@ -158,8 +445,9 @@ impl Client {
         );
 
         let bytes = message_to_bytes(kind, message)?;
+        let len = bytes.len();
 
-        self.send(bytes.as_ref()).await?;
+        self.send(bytes)?;
 
         crate::metrics::MESSAGES_TOTAL
             .with_label_values(&["tcp", "output", kind.to_string().as_str()])
@@ -167,7 +455,7 @@ impl Client {
 
         crate::metrics::MESSAGES_BYTES
             .with_label_values(&["tcp", "output", kind.to_string().as_str()])
-            .inc_by(bytes.len() as u64);
+            .inc_by(len as u64);
 
         Ok(())
     }
@@ -178,11 +466,18 @@ impl Client {
         self.udp_socket_addr.swap(None)
     }
 
-    // TODO: If https://github.com/citizenfx/fivem/pull/2990 gets merged this should only send back
-    // the server nonce for unless the clients request a resync
+    /// Resynchronizes the nonces without changing the key: sends the server nonce and requests the
+    /// client nonce with an empty crypt setup
+    pub async fn send_crypt_resync(&self) -> Result<(), MumbleError> {
+        let crypt_setup = { self.crypt_state.lock().get_server_nonce_setup() };
+
+        self.send_message(MessageKind::CryptSetup, &crypt_setup).await?;
+        self.send_message(MessageKind::CryptSetup, &CryptSetup::new()).await
+    }
+
     pub async fn send_crypt_setup(&self, reset: bool) -> Result<(), MumbleError> {
         let crypt_setup = {
-            let mut crypt = self.crypt_state.lock().await;
+            let mut crypt = self.crypt_state.lock();
             if reset {
                 crypt.reset();
             }
@@ -200,6 +495,8 @@ impl Client {
     }
 
     pub async fn sync_client_and_channels(&self, state: &ServerStateRef) -> Result<(), MumbleError> {
+        let mut listening_channels: HashMap<u32, Vec<u32>> = HashMap::new();
+
         // Send channel states
         let mut iter = state.channels.first_entry_async().await;
         while let Some(channel) = iter {
@@ -207,13 +504,22 @@ impl Client {
 
             self.send_message(MessageKind::ChannelState, channel_state.as_ref()).await?;
 
+            channel
+                .listeners
+                .scan_async(|session_id, _| listening_channels.entry(*session_id).or_default().push(channel.id))
+                .await;
+
             iter = channel.next_async().await;
         }
 
         // send client sates
         let mut iter = state.clients.first_entry_async().await;
         while let Some(client) = iter {
-            let user_state = client.get_user_state();
+            let mut user_state = client.get_user_state();
+
+            if let Some(channels) = listening_channels.remove(&client.session_id) {
+                user_state.set_listening_channel_add(channels);
+            }
 
             self.send_message(MessageKind::UserState, &user_state).await?;
 
@@ -223,39 +529,45 @@ impl Client {
         Ok(())
     }
 
-    pub async fn send_server_sync(&self) -> Result<(), MumbleError> {
+    pub async fn send_server_sync(&self, max_bandwidth: u32) -> Result<(), MumbleError> {
         let mut server_sync = ServerSync::default();
-        server_sync.set_max_bandwidth(MAX_BANDWIDTH_IN_BITS);
+        server_sync.set_max_bandwidth(max_bandwidth);
         server_sync.set_session(self.session_id);
         server_sync.set_welcome_text("SoZ Mumble Server".to_string());
 
         self.send_message(MessageKind::ServerSync, &server_sync).await
     }
 
-    pub async fn send_server_config(&self) -> Result<(), MumbleError> {
+    /// Sends the limits and features of the server, the extended udp ping has no field for them
+    pub async fn send_server_config(&self, config: &Config) -> Result<(), MumbleError> {
         let mut server_config = ServerConfig::default();
+        server_config.set_max_bandwidth(config.max_bandwidth);
+        server_config.set_max_users(config.max_clients as u32);
         server_config.set_allow_html(false);
         server_config.set_message_length(0);
         server_config.set_image_message_length(0);
+        // the recording flag of the clients is stored and broadcast
+        server_config.set_recording_allowed(true);
 
         self.send_message(MessageKind::ServerConfig, &server_config).await
     }
 
-    pub async fn send_voice_packet(&self, packet: Arc<VoicePacket<ClientBound>>) -> Result<(), MumbleError> {
-        if let Some(addr) = self.udp_socket_addr.load_full() {
+    pub async fn send_voice_packet(&self, packet: Arc<SharedVoicePacket>) -> Result<(), MumbleError> {
+        // the packet is only encoded once for all the recipients using the same voice protocol
+        let Some(encoded) = packet.encoded(self.voice_protocol) else {
+            return Err(MumbleError::UnsupportedVoiceCodec);
+        };
+
+        if let (Transport::Udp, Some(addr)) = (self.get_transport(), self.udp_socket_addr.load_full()) {
             let mut dest = BytesMut::new();
 
             {
-                self.crypt_state.lock().await.encrypt(&packet, &mut dest);
+                self.crypt_state.lock().encrypt_bytes(encoded, &mut dest);
             }
 
-            let buf = &dest.freeze()[..];
+            let len = dest.len();
 
-            match timeout(Duration::from_millis(250), self.udp_socket.send_to(buf, addr.as_ref())).await {
-                Ok(Ok(_)) => Ok(()),
-                Ok(Err(e)) => Err(MumbleError::Io(e)),
-                Err(_) => Err(MumbleError::PacketDiscarded),
-            }?;
+            self.udp_writer.send(*addr, dest.freeze())?;
 
             crate::metrics::MESSAGES_TOTAL
                 .with_label_values(&["udp", "output", "VoicePacket"])
@@ -263,28 +575,119 @@ impl Client {
 
             crate::metrics::MESSAGES_BYTES
                 .with_label_values(&["udp", "output", "VoicePacket"])
-                .inc_by(buf.len() as u64);
+                .inc_by(len as u64);
 
             return Ok(());
         }
 
-        let mut data = BytesMut::new();
-        encode_voice_packet(&packet, &mut data);
-        let bytes = data.freeze();
-
         let mut tunnel_message = UDPTunnel::default();
-        tunnel_message.set_packet(bytes.to_vec());
+        tunnel_message.set_packet(encoded.to_vec());
 
         self.send_message(MessageKind::UDPTunnel, &tunnel_message).await
     }
 
-    pub fn update(&self, state: &UserState) {
-        if state.has_mute() {
+    /// Applies the fields of the user state to the client and returns the changes to broadcast to the
+    /// other clients, or none if nothing needs to be broadcast. The channel and the listening channels
+    /// are handled by the server state.
+    ///
+    /// The moderation fields (mute, deaf, suppress and priority speaker) are only applied for a
+    /// moderator (an admin or the server itself), they are ignored otherwise.
+    pub fn update(&self, state: &UserState, moderator: bool) -> Option<UserState> {
+        let mut changes = UserState::new();
+        let mut changed = false;
+
+        changes.set_session(self.session_id);
+
+        let moderate = |has_field: bool| moderator && has_field;
+
+        if moderate(state.has_deaf()) {
+            self.set_deaf(state.get_deaf());
+
+            // a deafened user is always muted
+            if state.get_deaf() {
+                self.set_mute(true);
+            }
+        }
+
+        if moderate(state.has_mute()) {
             self.set_mute(state.get_mute());
+
+            // and unmuting a user undeafens it
+            if !state.get_mute() {
+                self.set_deaf(false);
+            }
         }
 
-        if state.has_deaf() {
-            self.set_deaf(state.get_deaf());
+        if moderate(state.has_mute() || state.has_deaf()) {
+            changes.set_mute(self.is_muted());
+            changes.set_deaf(self.is_deaf());
+            changed = true;
+        }
+
+        // same rules for the state set by the user itself
+        if state.has_self_deaf() {
+            self.self_deaf.store(state.get_self_deaf(), Ordering::Release);
+
+            if state.get_self_deaf() {
+                self.self_mute.store(true, Ordering::Release);
+            }
+        }
+
+        if state.has_self_mute() {
+            self.self_mute.store(state.get_self_mute(), Ordering::Release);
+
+            if !state.get_self_mute() {
+                self.self_deaf.store(false, Ordering::Release);
+            }
+        }
+
+        if state.has_self_mute() || state.has_self_deaf() {
+            changes.set_self_mute(self.is_self_muted());
+            changes.set_self_deaf(self.is_self_deaf());
+            changed = true;
+        }
+
+        if moderate(state.has_suppress()) {
+            self.suppress.store(state.get_suppress(), Ordering::Release);
+            changes.set_suppress(state.get_suppress());
+            changed = true;
+        }
+
+        if moderate(state.has_priority_speaker()) {
+            self.set_priority_speaker(state.get_priority_speaker());
+            changes.set_priority_speaker(state.get_priority_speaker());
+            changed = true;
+        }
+
+        if state.has_recording() {
+            self.recording.store(state.get_recording(), Ordering::Release);
+            changes.set_recording(state.get_recording());
+            changed = true;
+        }
+
+        if state.has_comment() || state.has_plugin_context() || state.has_plugin_identity() {
+            let mut details = self.details.lock();
+
+            if state.has_comment() {
+                details.comment = state.get_comment().to_string();
+                changes.set_comment(details.comment.clone());
+                changed = true;
+            }
+
+            // plugin data is private to the client, it's stored but never sent to the other clients
+            if state.has_plugin_context() {
+                details.plugin_context = state.get_plugin_context().to_vec();
+            }
+
+            if state.has_plugin_identity() {
+                details.plugin_identity = state.get_plugin_identity().to_string();
+            }
+        }
+
+        if changed {
+            Some(changes)
+        } else {
+            None
         }
     }
 
@@ -307,6 +710,19 @@ impl Client {
         user_state.set_channel_id(self.channel_id.load(Ordering::Relaxed));
         user_state.set_session(self.session_id);
         user_state.set_name(self.get_name().as_ref().clone());
+        user_state.set_mute(self.is_muted());
+        user_state.set_deaf(self.is_deaf());
+        user_state.set_suppress(self.is_suppressed());
+        user_state.set_self_mute(self.is_self_muted());
+        user_state.set_self_deaf(self.is_self_deaf());
+        user_state.set_recording(self.is_recording());
+        user_state.set_priority_speaker(self.is_priority_speaker());
+
+        let comment = { self.details.lock().comment.clone() };
+
+        if !comment.is_empty() {
+            user_state.set_comment(comment);
+        }
 
         user_state
     }
@@ -0,0 +1,344 @@
+use crate::proto::{parse_version_v2, MessageKind};
+use crate::server::constants::{MAX_BANDWIDTH_IN_BITS, MAX_CLIENTS};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Server configuration, read from the toml file given with `--config`
+///
+/// Every value is optional and falls back to its default when missing from the file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The amount of players the server can support
+    pub max_clients: usize,
+    /// The bandwidth (in bits) that the client can use
+    pub max_bandwidth: u32,
+    /// Amount of udp sockets (sharing the listen address with SO_REUSEPORT) and receive loops,
+    /// defaults to the number of cpus
+    pub udp_workers: Option<usize>,
+    /// Proximity voice routing based on positional audio
+    pub proximity: ProximityConfig,
+    /// Isolation of the routing buckets (instances) of the game
+    pub buckets: BucketConfig,
+    /// Radio frequencies managed with the http api
+    pub radio: VoiceGroupConfig,
+    /// Phone calls managed with the http api
+    pub call: VoiceGroupConfig,
+    /// Handling of the audio of other speakers while a priority speaker talks
+    pub priority_speaker: PrioritySpeakerConfig,
+    /// Outbound queue of each client
+    pub client_queue: ClientQueueConfig,
+    /// Tcp connections of the clients
+    pub tcp: TcpConfig,
+    /// Resynchronization of the voice encryption of the clients
+    pub crypt: CryptConfig,
+    /// Tunneling of the voice packets through tcp for clients which don't receive udp
+    pub udp_fallback: UdpFallbackConfig,
+    /// Clients older than this version (`major.minor.patch`) are rejected, every client is accepted
+    /// when not set
+    pub min_client_version: Option<String>,
+    /// Clients authenticating with this password are admins: they can mute, deafen, suppress, move
+    /// and set the priority speaker flag of the other clients. No client is admin when not set.
+    pub admin_password: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_clients: MAX_CLIENTS,
+            max_bandwidth: MAX_BANDWIDTH_IN_BITS,
+            udp_workers: None,
+            proximity: ProximityConfig::default(),
+            buckets: BucketConfig::default(),
+            radio: VoiceGroupConfig::default(),
+            call: VoiceGroupConfig::default(),
+            priority_speaker: PrioritySpeakerConfig::default(),
+            client_queue: ClientQueueConfig::default(),
+            tcp: TcpConfig::default(),
+            crypt: CryptConfig::default(),
+            udp_fallback: UdpFallbackConfig::default(),
+            min_client_version: None,
+            admin_password: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: Option<&str>) -> Result<Self, anyhow::Error> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("cannot read config file {}: {}", path, e))?;
+
+        let config: Self = toml::from_str(&content).map_err(|e| anyhow::anyhow!("invalid config file {}: {}", path, e))?;
+
+        for (name, group) in [("radio", &config.radio), ("call", &config.call)] {
+            if let Some(target) = group.target {
+                // mumble spec limits the usable voice targets to 1..=30
+                if !(1..=30).contains(&target) {
+                    anyhow::bail!("invalid config file {}: {}.target must be between 1 and 30", path, name);
+                }
+            }
+        }
+
+        if let Some(min_client_version) = &config.min_client_version {
+            if parse_version_v2(min_client_version).is_none() {
+                anyhow::bail!(
+                    "invalid config file {}: min_client_version must be formatted as major.minor.patch",
+                    path
+                );
+            }
+        }
+
+        if config.udp_workers == Some(0) {
+            anyhow::bail!("invalid config file {}: udp_workers must be at least 1", path);
+        }
+
+        if config.client_queue.voice_capacity == 0 {
+            anyhow::bail!("invalid config file {}: client_queue.voice_capacity must be at least 1", path);
+        }
+
+        if config.tcp.write_timeout_ms == 0 {
+            anyhow::bail!("invalid config file {}: tcp.write_timeout_ms must be at least 1", path);
+        }
+
+        for name in config.tcp.frame_limits.keys() {
+            if !(0..=25).any(|kind| MessageKind::try_from(kind).is_ok_and(|kind| kind.to_string() == *name)) {
+                anyhow::bail!("invalid config file {}: unknown message kind {} in tcp.frame_limits", path, name);
+            }
+        }
+
+        if config.radio.target.is_some() && config.radio.target == config.call.target {
+            anyhow::bail!("invalid config file {}: radio and call can't use the same voice target", path);
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the minimum client version in the `version_v2` format, if any
+    pub fn get_min_client_version(&self) -> Option<u64> {
+        self.min_client_version.as_deref().and_then(parse_version_v2)
+    }
+
+    pub fn get_udp_workers(&self) -> usize {
+        self.udp_workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProximityConfig {
+    /// Only deliver channel audio to the listeners within the radius of the speaker
+    pub enabled: bool,
+    /// Maximum distance between the speaker and the listener, in the unit of the game positions
+    pub radius: f32,
+    /// Per channel (by name) radius overrides, a radius of 0 disables proximity for this channel
+    pub channels: HashMap<String, f32>,
+    /// Where the positions used for proximity come from
+    pub source: PositionSource,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionSource {
+    /// Positional audio information sent by the clients in their voice packets
+    Client,
+    /// Positions pushed by the game server through the http api, client positions are ignored
+    Server,
+}
+
+impl Default for ProximityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            radius: 50.0,
+            channels: HashMap::new(),
+            source: PositionSource::Client,
+        }
+    }
+}
+
+impl ProximityConfig {
+    /// Returns the radius to use for channel audio in this channel, if proximity is enabled for it
+    pub fn get_radius(&self, channel_name: &str) -> Option<f32> {
+        if !self.enabled {
+            return None;
+        }
+
+        let radius = self.channels.get(channel_name).copied().unwrap_or(self.radius);
+
+        if radius > 0.0 {
+            Some(radius)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketConfig {
+    /// Voice targets (1-30) whose audio is delivered to every bucket, like radios
+    pub cross_bucket_targets: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoiceGroupConfig {
+    /// Voice target (1-30) used by clients to talk to the other members of their groups, the groups
+    /// are disabled when not set. Any voice target registered by the client with this id is ignored.
+    pub target: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PriorityMode {
+    /// The audio of the other speakers is delivered unchanged
+    Off,
+    /// The audio of the other speakers is delivered with a lower volume, only clients using the
+    /// protobuf voice protocol (1.5+) support it, the others receive the audio unchanged
+    Duck,
+    /// The audio of the other speakers isn't delivered at all
+    Suppress,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrioritySpeakerConfig {
+    /// What happens to the audio of the other speakers for the listeners of a priority speaker
+    pub mode: PriorityMode,
+    /// Volume factor applied to the audio of the other speakers when ducking
+    pub duck_volume: f32,
+    /// How long (in milliseconds) the other speakers stay ducked or suppressed after the last voice
+    /// packet of the priority speaker
+    pub hold_ms: u64,
+}
+
+impl Default for PrioritySpeakerConfig {
+    fn default() -> Self {
+        Self {
+            mode: PriorityMode::Off,
+            duck_volume: 0.2,
+            hold_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientQueueConfig {
+    /// Amount of voice packets waiting to be sent to a client, the oldest ones are dropped when full.
+    /// Control messages are never dropped.
+    pub voice_capacity: usize,
+    /// Clients whose oldest queued message waits for longer than this (in milliseconds) are
+    /// disconnected as slow consumers
+    pub max_backlog_ms: u64,
+}
+
+impl Default for ClientQueueConfig {
+    fn default() -> Self {
+        Self {
+            voice_capacity: 1024,
+            max_backlog_ms: 10000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Clients which don't accept a write within this time (in milliseconds) are disconnected
+    pub write_timeout_ms: u64,
+    /// Maximum size (in bytes) of the payload of a frame sent by a client, clients sending a larger
+    /// frame are disconnected
+    pub max_frame_size: u32,
+    /// Per message kind (by name, like `UDPTunnel`) maximum frame size overrides
+    pub frame_limits: HashMap<String, u32>,
+    /// Time (in milliseconds) given to a new connection to complete the TLS handshake
+    pub tls_timeout_ms: u64,
+    /// Time (in milliseconds) given to a client to send its version once connected
+    pub version_timeout_ms: u64,
+    /// Time (in milliseconds) given to a client to authenticate once its version is received
+    pub authenticate_timeout_ms: u64,
+    /// Time (in milliseconds) given to the server to send the channels, users and server sync to a
+    /// newly authenticated client
+    pub sync_timeout_ms: u64,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            write_timeout_ms: 10000,
+            max_frame_size: 8192,
+            frame_limits: HashMap::new(),
+            tls_timeout_ms: 5000,
+            version_timeout_ms: 10000,
+            authenticate_timeout_ms: 10000,
+            sync_timeout_ms: 10000,
+        }
+    }
+}
+
+impl TcpConfig {
+    /// Returns the maximum size of the payload of a frame of this kind
+    pub fn get_max_frame_size(&self, kind: MessageKind) -> u32 {
+        self.frame_limits
+            .get(kind.to_string().as_str())
+            .copied()
+            .unwrap_or(self.max_frame_size)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CryptConfig {
+    /// Clients with a udp connection which sent no udp ping for this long (in milliseconds) get
+    /// their crypt state resynchronized, silent clients keep sending pings
+    pub udp_ping_timeout_ms: u64,
+    /// Amount of resynchronizations only exchanging the nonces before the key is regenerated, 0
+    /// always regenerates the key (for clients which don't support nonce resynchronization)
+    pub nonce_resyncs: u32,
+    /// Delay (in milliseconds) before a failed resynchronization is retried, doubled after each
+    /// failure
+    pub backoff_ms: u64,
+    /// Maximum delay (in milliseconds) between two resynchronizations
+    pub max_backoff_ms: u64,
+}
+
+impl Default for CryptConfig {
+    fn default() -> Self {
+        Self {
+            udp_ping_timeout_ms: 8000,
+            nonce_resyncs: 2,
+            backoff_ms: 5000,
+            max_backoff_ms: 60000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpFallbackConfig {
+    /// Tunnel the voice packets through tcp for clients which report not receiving our udp packets
+    pub enabled: bool,
+    /// Consecutive pings of the client reporting no received packet before falling back to tcp
+    pub failed_reports: u32,
+    /// How long (in milliseconds) the voice packets are tunneled before probing udp again, doubled
+    /// each time udp still doesn't work
+    pub probe_ms: u64,
+    /// Maximum delay (in milliseconds) between two probes
+    pub max_probe_ms: u64,
+}
+
+impl Default for UdpFallbackConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            failed_reports: 2,
+            probe_ms: 30000,
+            max_probe_ms: 300000,
+        }
+    }
+}
@@ -1,19 +1,19 @@
-use crate::error::DecryptError;
+use crate::error::{DecryptError, MumbleError};
 use crate::proto::mumble::CryptSetup;
-use crate::voice::{decode_voice_packet, encode_voice_packet, VoicePacket, VoicePacketDst};
+use crate::voice::{VoicePacket, VoicePacketDst, VoiceProtocol};
 use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
 use aes::Aes128;
 use bytes::BytesMut;
 use ring::rand::{SecureRandom, SystemRandom};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref SYSTEM_RANDOM: SystemRandom = SystemRandom::new();
 }
 
-const KEY_SIZE: usize = 16;
-const BLOCK_SIZE: usize = std::mem::size_of::<u128>();
+pub const KEY_SIZE: usize = 16;
+pub const BLOCK_SIZE: usize = std::mem::size_of::<u128>();
 
 pub struct CryptState {
     pub key: [u8; KEY_SIZE],
@@ -27,9 +27,82 @@ pub struct CryptState {
     pub late: u32,
     pub lost: u32,
     pub resync: u32,
+    /// Packets encrypted for the client, to compare with the packets it reports as received
+    pub sent: u32,
     pub last_good: Instant,
 }
 
+/// Why the crypt state of a client was resynchronized
+#[derive(Debug, Clone, Copy)]
+pub enum ResyncCause {
+    /// A udp packet of the client couldn't be decrypted
+    DecryptFailure,
+    /// Too many udp packets of the client arrived late
+    LateOverflow,
+    /// No udp packet of the client could be decrypted for a while, found by the clean loop
+    Timeout,
+    /// The client sent an empty crypt setup to get a new nonce, after failing to decrypt the packets
+    /// of the server
+    ClientRequest,
+}
+
+/// Exponential backoff between the resynchronizations of the crypt state of a client
+pub struct ResyncBackoff {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Default for ResyncBackoff {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            next_attempt: Instant::now(),
+        }
+    }
+}
+
+impl ResyncBackoff {
+    /// Returns the amount of resyncs already attempted since the crypt state was last healthy if a
+    /// new one can be attempted now, the delay before the next one doubles each time
+    pub fn try_attempt(&mut self, now: Instant, base: Duration, max: Duration) -> Option<u32> {
+        if now < self.next_attempt {
+            return None;
+        }
+
+        let attempts = self.attempts;
+        let delay = base.saturating_mul(1 << attempts.min(16)).min(max);
+
+        self.attempts += 1;
+        self.next_attempt = now + delay;
+
+        Some(attempts)
+    }
+
+    /// The crypt state works again, the next failure is resynced right away
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.next_attempt = Instant::now();
+    }
+}
+
+impl ResyncCause {
+    pub const ALL: [ResyncCause; 4] = [
+        ResyncCause::DecryptFailure,
+        ResyncCause::LateOverflow,
+        ResyncCause::Timeout,
+        ResyncCause::ClientRequest,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResyncCause::DecryptFailure => "decrypt_failure",
+            ResyncCause::LateOverflow => "late_overflow",
+            ResyncCause::Timeout => "timeout",
+            ResyncCause::ClientRequest => "client_request",
+        }
+    }
+}
+
 impl Default for CryptState {
     fn default() -> Self {
         let mut key = [0u8; KEY_SIZE];
@@ -40,12 +113,13 @@ impl Default for CryptState {
             key,
             encrypt_nonce: 0,
             decrypt_nonce: 1 << 127,
-            decrypt_history: [0; 0x100],
+            decrypt_history: empty_history(1 << 127),
 
             good: 0,
             late: 0,
             lost: 0,
             resync: 0,
+            sent: 0,
             last_good: Instant::now(),
         }
     }
@@ -55,11 +129,12 @@ impl CryptState {
     pub fn reset(&mut self) {
         self.encrypt_nonce = 0;
         self.decrypt_nonce = 1 << 127;
-        self.decrypt_history = [0; 0x100];
+        self.decrypt_history = empty_history(self.decrypt_nonce);
         self.good = 0;
         self.late = 0;
         self.lost = 0;
         self.resync = 0;
+        self.sent = 0;
         self.last_good = Instant::now();
     }
 
@@ -73,9 +148,33 @@ impl CryptState {
         self.decrypt_nonce.to_le_bytes()
     }
 
-    pub fn set_decrypt_nonce(&mut self, nonce: &[u8]) {
-        self.decrypt_nonce = u128::from_le_bytes(nonce.try_into().unwrap());
+    /// Sets the nonce used for decrypting, sent by the client when it can't decrypt our packets
+    /// anymore, nonces which aren't a block long are rejected.
+    pub fn set_decrypt_nonce(&mut self, nonce: &[u8]) -> Result<(), MumbleError> {
+        let nonce: [u8; BLOCK_SIZE] = nonce.try_into().map_err(|_| MumbleError::InvalidNonce(nonce.len()))?;
+
+        self.decrypt_nonce = u128::from_le_bytes(nonce);
         self.resync += 1;
+
+        Ok(())
+    }
+
+    /// Sets the key and both nonces, like a client receiving the `CryptSetup` of the server.
+    pub fn set_key(&mut self, key: [u8; KEY_SIZE], encrypt_nonce: [u8; BLOCK_SIZE], decrypt_nonce: [u8; BLOCK_SIZE]) {
+        self.aes = Aes128::new(GenericArray::from_slice(&key));
+        self.key = key;
+        self.encrypt_nonce = u128::from_le_bytes(encrypt_nonce);
+        self.decrypt_nonce = u128::from_le_bytes(decrypt_nonce);
+        self.decrypt_history = empty_history(self.decrypt_nonce);
+    }
+
+    /// Returns a crypt setup only carrying the server nonce, it lets the client decrypt our packets
+    /// again without changing the key
+    pub fn get_server_nonce_setup(&self) -> CryptSetup {
+        let mut crypt_setup = CryptSetup::new();
+        crypt_setup.set_server_nonce(self.get_encrypt_nonce().to_vec());
+
+        crypt_setup
     }
 
     pub fn get_crypt_setup(&self) -> CryptSetup {
@@ -89,24 +188,53 @@ impl CryptState {
     }
 
     /// Encrypts an encoded voice packet and returns the resulting bytes.
-    pub fn encrypt<EncodeDst: VoicePacketDst>(&mut self, packet: &VoicePacket<EncodeDst>, dst: &mut BytesMut) {
-        self.encrypt_nonce = self.encrypt_nonce.wrapping_add(1);
-
+    pub fn encrypt<EncodeDst: VoicePacketDst>(
+        &mut self,
+        protocol: VoiceProtocol,
+        packet: &VoicePacket<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), MumbleError> {
         // Leave four bytes for header
         dst.resize(4, 0);
         let mut inner = dst.split_off(4);
 
-        encode_voice_packet(packet, &mut inner);
-
-        let tag = self.ocb_encrypt(inner.as_mut());
+        // encode before touching the nonce so a packet we can't encode isn't seen as lost
+        protocol.encode(packet, &mut inner)?;
         dst.unsplit(inner);
 
+        self.encrypt_in_place(dst);
+
+        Ok(())
+    }
+
+    /// Encrypts a voice packet already encoded in the voice protocol of the client, used when the
+    /// same packet is sent to many clients (see [crate::voice::SharedVoicePacket]).
+    pub fn encrypt_bytes(&mut self, encoded: &[u8], dst: &mut BytesMut) {
+        dst.reserve(4 + encoded.len());
+        // Leave four bytes for header
+        dst.resize(4, 0);
+        dst.extend_from_slice(encoded);
+
+        self.encrypt_in_place(dst);
+    }
+
+    /// Encrypts the packet after the four bytes of the header, then writes the header.
+    fn encrypt_in_place(&mut self, dst: &mut BytesMut) {
+        self.encrypt_nonce = self.encrypt_nonce.wrapping_add(1);
+        self.sent = self.sent.wrapping_add(1);
+
+        let tag = self.ocb_encrypt(&mut dst[4..]);
+
         dst[0] = self.encrypt_nonce as u8;
         dst[1..4].copy_from_slice(&tag.to_be_bytes()[0..3]);
     }
 
     /// Decrypts a voice packet and (if successful) returns the `Result` of parsing the packet.
-    pub fn decrypt<DecodeDst: VoicePacketDst>(&mut self, buf: &mut BytesMut) -> Result<VoicePacket<DecodeDst>, DecryptError> {
+    pub fn decrypt<DecodeDst: VoicePacketDst>(
+        &mut self,
+        protocol: VoiceProtocol,
+        buf: &mut BytesMut,
+    ) -> Result<VoicePacket<DecodeDst>, DecryptError> {
         if buf.len() < 4 {
             return Err(DecryptError::Eof);
         }
@@ -144,9 +272,12 @@ impl CryptState {
             }
         }
 
-        let tag = self.ocb_decrypt(buf.as_mut());
+        let valid = match self.ocb_decrypt(buf.as_mut()) {
+            Some(tag) => ring::constant_time::verify_slices_are_equal(&header[1..4], &tag.to_be_bytes()[0..3]).is_ok(),
+            None => false,
+        };
 
-        if Ok(()) != ring::constant_time::verify_slices_are_equal(&header[1..4], &tag.to_be_bytes()[0..3]) {
+        if !valid {
             self.decrypt_nonce = saved_nonce;
             return Err(DecryptError::Mac);
         }
@@ -160,9 +291,10 @@ impl CryptState {
             self.decrypt_nonce = saved_nonce;
         }
 
-        self.lost = (self.lost as i32 + lost) as u32;
+        // a late packet may arrive after a resync, which already forgot about the lost packets
+        self.lost = self.lost.saturating_add_signed(lost);
 
-        decode_voice_packet(buf)
+        protocol.decode(buf)
     }
 
     /// Encrypt the provided buffer using AES-OCB, returning the tag.
@@ -177,7 +309,16 @@ impl CryptState {
 
             offset = s2(offset);
 
-            let plain = u128::from_be_bytes(*chunk);
+            let mut plain = u128::from_be_bytes(*chunk);
+
+            // counter-cryptanalysis of section 9 of https://eprint.iacr.org/2019/311 (like murmur
+            // 1.3.4), the block before the last one must not look like the length of a block,
+            // otherwise the packet could be used to forge others (see `ocb_decrypt`). Flipping a
+            // bit of a sound packet is harmless.
+            if buf.len() <= BLOCK_SIZE && plain >> 8 == 0 {
+                plain ^= 1 << 120;
+            }
+
             let encrypted = self.aes_encrypt(offset ^ plain) ^ offset;
             chunk.copy_from_slice(&encrypted.to_be_bytes());
 
@@ -200,9 +341,10 @@ impl CryptState {
         self.aes_encrypt(offset ^ s2(offset) ^ checksum)
     }
 
-    /// Decrypt the provided buffer using AES-OCB, returning the tag.
+    /// Decrypt the provided buffer using AES-OCB, returning the tag, or `None` if the packet is a
+    /// forgery.
     /// **Make sure to verify that the tag matches!**
-    fn ocb_decrypt(&self, mut buf: &mut [u8]) -> u128 {
+    fn ocb_decrypt(&self, mut buf: &mut [u8]) -> Option<u128> {
         let mut offset = self.aes_encrypt(self.decrypt_nonce.to_be());
         let mut checksum = 0u128;
 
@@ -230,9 +372,17 @@ impl CryptState {
         let plain = u128::from_be_bytes(block) ^ pad;
         buf.copy_from_slice(&plain.to_be_bytes()[..len]);
 
+        // in the attack of https://eprint.iacr.org/2019/311, the last block decrypts to the offset
+        // xored with the length of a block, the padding is checked too as forgeries of shorter
+        // blocks are also feasible. Such blocks are never sent since `ocb_encrypt` flips a bit of
+        // the block it would come from.
+        if (plain ^ offset) >> 8 == 0 {
+            return None;
+        }
+
         checksum ^= plain;
 
-        self.aes_encrypt(offset ^ s2(offset) ^ checksum)
+        Some(self.aes_encrypt(offset ^ s2(offset) ^ checksum))
     }
 
     /// AES-128 encryption primitive.
@@ -254,9 +404,296 @@ impl CryptState {
     }
 }
 
+/// Returns the history of a crypt state which received no packet yet.
+///
+/// The history keeps the second byte of the nonce of the last packet received for each value of
+/// the first byte, to find repeated packets. It must not match the nonces around the current one,
+/// otherwise late packets would be seen as repeated.
+fn empty_history(decrypt_nonce: u128) -> [u8; 0x100] {
+    [((decrypt_nonce >> 8) as u8).wrapping_add(0x80); 0x100]
+}
+
 #[inline]
 fn s2(block: u128) -> u128 {
     let rot = block.rotate_left(1);
     let carry = rot & 1;
     rot ^ (carry * 0x86)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice::ServerBound;
+
+    const KEY: [u8; KEY_SIZE] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    ];
+    const NONCE: [u8; BLOCK_SIZE] = KEY;
+
+    /// Legacy ping, the smallest valid voice packet
+    const PING: [u8; 2] = [0x20, 0x00];
+
+    fn crypt_state(key: [u8; KEY_SIZE], encrypt_nonce: [u8; BLOCK_SIZE], decrypt_nonce: [u8; BLOCK_SIZE]) -> CryptState {
+        let mut crypt_state = CryptState::default();
+        crypt_state.set_key(key, encrypt_nonce, decrypt_nonce);
+        crypt_state
+    }
+
+    /// Returns the crypt states of the client and the server, with the nonces of a new connection
+    fn pair() -> (CryptState, CryptState) {
+        let server = CryptState::default();
+        let client = crypt_state(server.key, server.get_decrypt_nonce(), server.get_encrypt_nonce());
+
+        (client, server)
+    }
+
+    fn encrypt(crypt_state: &mut CryptState, payload: &[u8]) -> BytesMut {
+        let mut dst = BytesMut::new();
+        crypt_state.encrypt_bytes(payload, &mut dst);
+        dst
+    }
+
+    fn decrypt(crypt_state: &mut CryptState, packet: &BytesMut) -> Result<VoicePacket<ServerBound>, DecryptError> {
+        crypt_state.decrypt(VoiceProtocol::Legacy, &mut packet.clone())
+    }
+
+    fn counters(crypt_state: &CryptState) -> (u32, u32, u32) {
+        (crypt_state.good, crypt_state.late, crypt_state.lost)
+    }
+
+    // test vectors of murmur (src/tests/TestCrypt), from draft-krovetz-ocb-00
+    #[test]
+    fn murmur_vectors() {
+        let mut crypt_state = crypt_state(KEY, NONCE, NONCE);
+
+        let tag = crypt_state.ocb_encrypt(&mut []);
+        assert_eq!(
+            tag.to_be_bytes(),
+            [0xbf, 0x31, 0x08, 0x13, 0x07, 0x73, 0xad, 0x5e, 0xc7, 0x0e, 0xc6, 0x9e, 0x78, 0x75, 0xa7, 0xb0]
+        );
+
+        let source: Vec<u8> = (0..40).collect();
+        let mut buf = source.clone();
+        let tag = crypt_state.ocb_encrypt(&mut buf);
+
+        assert_eq!(
+            tag.to_be_bytes(),
+            [0x9d, 0xb0, 0xcd, 0xf8, 0x80, 0xf7, 0x3e, 0x3e, 0x10, 0xd4, 0xeb, 0x32, 0x17, 0x76, 0x66, 0x88]
+        );
+        assert_eq!(
+            buf,
+            [
+                0xf7, 0x5d, 0x6b, 0xc8, 0xb4, 0xdc, 0x8d, 0x66, 0xb8, 0x36, 0xa2, 0xb0, 0x8b, 0x32, 0xa6, 0x36, 0x9f, 0x1c, 0xd3, 0xc5,
+                0x22, 0x8d, 0x79, 0xfd, 0x6c, 0x26, 0x7f, 0x5f, 0x6a, 0xa7, 0xb2, 0x31, 0xc7, 0xdf, 0xb9, 0xd5, 0x99, 0x51, 0xae, 0x9c
+            ]
+        );
+
+        crypt_state.decrypt_nonce = crypt_state.encrypt_nonce;
+        assert_eq!(crypt_state.ocb_decrypt(&mut buf), Some(tag));
+        assert_eq!(buf, source);
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut client, mut server) = pair();
+
+        for len in 0..=128 {
+            let mut payload = vec![0x80, 0x00, 0x00];
+            payload.extend((0..len).map(|i| i as u8));
+
+            let packet = encrypt(&mut client, &payload);
+            assert_eq!(packet.len(), payload.len() + 4);
+
+            // the bytes after the empty opus frame are the position info
+            match decrypt(&mut server, &packet).unwrap() {
+                VoicePacket::Audio { position_info, .. } => {
+                    assert_eq!(position_info.unwrap_or_default(), payload[3..], "length {}", len)
+                }
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+
+            // the server decrypting back what it sends
+            let packet = encrypt(&mut server, &PING);
+            assert!(decrypt(&mut client, &packet).is_ok());
+        }
+
+        assert_eq!(counters(&server), (129, 0, 0));
+        assert_eq!(counters(&client), (129, 0, 0));
+        assert_eq!(server.sent, 129);
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (mut client, mut server) = pair();
+
+        for index in 0..PING.len() + 4 {
+            let mut packet = encrypt(&mut client, &PING);
+            packet[index] ^= 0x01;
+
+            assert!(decrypt(&mut server, &packet).is_err());
+        }
+
+        // a rejected packet doesn't move the nonce, the next one decrypts
+        let mut other = crypt_state([0x42; KEY_SIZE], client.get_encrypt_nonce(), client.get_decrypt_nonce());
+        assert!(matches!(decrypt(&mut server, &encrypt(&mut other, &PING)), Err(DecryptError::Mac)));
+        assert!(decrypt(&mut server, &encrypt(&mut client, &PING)).is_ok());
+        assert_eq!(server.good, 1);
+    }
+
+    #[test]
+    fn lost_packets() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..10).map(|_| encrypt(&mut client, &PING)).collect();
+
+        for index in [0, 1, 4, 9] {
+            decrypt(&mut server, &packets[index]).unwrap();
+        }
+
+        assert_eq!(counters(&server), (4, 0, 6));
+    }
+
+    #[test]
+    fn reordered_packets() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..4).map(|_| encrypt(&mut client, &PING)).collect();
+
+        for index in [0, 2, 1, 3] {
+            decrypt(&mut server, &packets[index]).unwrap();
+        }
+
+        // the packet seen as lost arrived late
+        assert_eq!(counters(&server), (4, 1, 0));
+
+        // the late packet didn't move the nonce back
+        decrypt(&mut server, &encrypt(&mut client, &PING)).unwrap();
+        assert_eq!(counters(&server), (5, 1, 0));
+    }
+
+    #[test]
+    fn repeated_packets() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..3).map(|_| encrypt(&mut client, &PING)).collect();
+
+        decrypt(&mut server, &packets[0]).unwrap();
+        decrypt(&mut server, &packets[2]).unwrap();
+        decrypt(&mut server, &packets[1]).unwrap();
+
+        for packet in &packets {
+            assert!(matches!(decrypt(&mut server, packet), Err(DecryptError::Repeat)));
+        }
+
+        assert_eq!(counters(&server), (3, 1, 0));
+    }
+
+    #[test]
+    fn late_window() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..=40).map(|_| encrypt(&mut client, &PING)).collect();
+
+        decrypt(&mut server, &packets[40]).unwrap();
+        assert_eq!(counters(&server), (1, 0, 40));
+
+        // packets late by less than 30 are accepted, older ones are dropped
+        decrypt(&mut server, &packets[11]).unwrap();
+        assert!(matches!(decrypt(&mut server, &packets[10]), Err(DecryptError::Late)));
+        assert!(matches!(decrypt(&mut server, &packets[0]), Err(DecryptError::Late)));
+
+        assert_eq!(counters(&server), (2, 1, 39));
+    }
+
+    #[test]
+    fn packets_ahead() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..=254).map(|_| encrypt(&mut client, &PING)).collect();
+
+        // up to 127 packets may be lost, further packets are seen as late ones
+        decrypt(&mut server, &packets[126]).unwrap();
+        assert_eq!(counters(&server), (1, 0, 126));
+
+        assert!(matches!(decrypt(&mut server, &packets[126 + 128]), Err(DecryptError::Late)));
+        decrypt(&mut server, &packets[200]).unwrap();
+        assert_eq!(counters(&server), (2, 0, 199));
+    }
+
+    #[test]
+    fn nonce_wraparound() {
+        // the first byte of the nonce wraps after the first packets, and the whole nonce after
+        // the next ones
+        for nonce in [[0xfd, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], [0xfd; BLOCK_SIZE]] {
+            let mut client = crypt_state(KEY, nonce, nonce);
+            let mut server = crypt_state(KEY, nonce, nonce);
+
+            let packets: Vec<_> = (0..8).map(|_| encrypt(&mut client, &PING)).collect();
+
+            for index in [0, 1, 3, 2, 4, 7, 6] {
+                decrypt(&mut server, &packets[index]).unwrap();
+            }
+
+            assert!(matches!(decrypt(&mut server, &packets[2]), Err(DecryptError::Repeat)));
+            assert_eq!(counters(&server), (7, 2, 1));
+            assert_eq!(server.get_decrypt_nonce(), client.get_encrypt_nonce());
+        }
+    }
+
+    #[test]
+    fn late_packet_after_resync() {
+        let (mut client, mut server) = pair();
+
+        let packets: Vec<_> = (0..3).map(|_| encrypt(&mut client, &PING)).collect();
+
+        decrypt(&mut server, &packets[0]).unwrap();
+        decrypt(&mut server, &packets[2]).unwrap();
+        server.reset();
+        server.set_decrypt_nonce(&client.get_encrypt_nonce()).unwrap();
+
+        decrypt(&mut server, &packets[1]).unwrap();
+        assert_eq!(counters(&server), (1, 1, 0));
+    }
+
+    #[test]
+    fn invalid_nonce() {
+        let mut crypt_state = CryptState::default();
+        let nonce = crypt_state.get_decrypt_nonce();
+
+        assert!(crypt_state.set_decrypt_nonce(&[0x01; 15]).is_err());
+        assert!(crypt_state.set_decrypt_nonce(&[0x01; 17]).is_err());
+        assert!(crypt_state.set_decrypt_nonce(&[]).is_err());
+        assert_eq!(crypt_state.get_decrypt_nonce(), nonce);
+        assert_eq!(crypt_state.resync, 0);
+    }
+
+    // attack of section 4 of https://eprint.iacr.org/2019/311, from murmur's xexstarAttack test
+    #[test]
+    fn xexstar_forgery_is_rejected() {
+        let (mut client, mut server) = pair();
+
+        // the first block of a two blocks packet set to the length of a block: its encryption is the
+        // pad of a one block packet, giving away the plain text of any such packet
+        let len = (BLOCK_SIZE * 8) as u128;
+        let mut payload = len.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[42; BLOCK_SIZE]);
+
+        // without the countermeasure, the forged single block decrypts to the offset xored with the
+        // length
+        let nonce = client.encrypt_nonce.wrapping_add(1);
+        let offset = s2(client.aes_encrypt(nonce.to_be()));
+        let encrypted = client.aes_encrypt(offset ^ len) ^ offset;
+
+        let mut forged = (encrypted ^ len).to_be_bytes();
+        server.decrypt_nonce = nonce;
+        assert_eq!(server.ocb_decrypt(&mut forged), None);
+
+        // the block is altered when encrypted, the packet is still delivered
+        let mut packet = encrypt(&mut client, &payload);
+        server.decrypt_nonce = client.encrypt_nonce;
+        let mut decrypted = packet.split_off(4);
+        assert!(server.ocb_decrypt(&mut decrypted).is_some());
+        assert_eq!(decrypted[0], 0x01);
+        assert_eq!(decrypted[1..], payload[1..]);
+    }
+}
@@ -1,6 +1,8 @@
 use thiserror::Error;
 
 use crate::message::ClientMessage;
+use crate::proto::MessageKind;
+use crate::server::HandshakeStage;
 
 #[derive(Error, Debug)]
 pub enum MumbleError {
@@ -22,6 +24,20 @@ pub enum MumbleError {
     ChannelDoesntExist,
     #[error("voice packet took to long to send, discarding")]
     PacketDiscarded,
+    #[error("voice packet codec can't be encoded in the client voice protocol")]
+    UnsupportedVoiceCodec,
+    #[error("client queue is closed")]
+    QueueClosed,
+    #[error("client tcp writer is closed")]
+    WriterClosed,
+    #[error("client tcp writer queue is full")]
+    WriterFull,
+    #[error("{kind} frame of {size} bytes is larger than the limit of {max_size} bytes")]
+    FrameTooLarge { kind: MessageKind, size: u32, max_size: u32 },
+    #[error("handshake timed out during the {0} stage")]
+    HandshakeTimeout(HandshakeStage),
+    #[error("nonce of {0} bytes, expected 16 bytes")]
+    InvalidNonce(usize),
 }
 
 // impl actix_web::error::ResponseError for MumbleError {}
@@ -38,4 +54,6 @@ pub enum DecryptError {
     Late,
     #[error("mac error")]
     Mac,
+    #[error("protobuf error: {0}")]
+    Parse(#[from] protobuf::ProtobufError),
 }
@@ -0,0 +1,162 @@
+use scc::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::client::ClientRef;
+use crate::recipients::Recipients;
+
+pub type VoiceGroupRef = Arc<VoiceGroup>;
+
+pub struct GroupMember {
+    pub client: ClientRef,
+    /// The member audio is sent to the other members of the group
+    pub transmit: bool,
+    /// The member hears the audio of the other members of the group
+    pub receive: bool,
+}
+
+/// A group of clients talking to each other on a dedicated voice target, used for radio frequencies
+/// and phone calls
+#[derive(Default)]
+pub struct VoiceGroup {
+    pub members: HashMap<u32, GroupMember>,
+    /// Members receiving the audio of the group which can hear
+    pub receivers: Recipients,
+}
+
+/// All the groups of one kind (radio frequencies or calls), by id
+#[derive(Default)]
+pub struct VoiceGroups {
+    pub groups: HashMap<u32, VoiceGroupRef>,
+    /// Receivers of all the groups each transmitting member is part of, by session id
+    recipients: HashMap<u32, Recipients>,
+    /// Held while rebuilding the recipients of a member, so an older list never replaces a newer one
+    member_refresh: Mutex<()>,
+}
+
+impl VoiceGroups {
+    /// Adds the client to the group, creating it if needed, or updates its flags if it's already a member
+    pub async fn join(&self, id: u32, client: ClientRef, transmit: bool, receive: bool) {
+        let session_id = client.session_id;
+        let member = GroupMember { client, transmit, receive };
+
+        let group = {
+            // the member is added while holding the entry, otherwise `leave` could remove the group
+            // (still empty) before the member is added to it
+            let entry = self.groups.entry_async(id).await.or_default();
+            entry.get().members.upsert(session_id, member);
+            entry.get().clone()
+        };
+
+        self.refresh_group(&group).await;
+        // the member may no longer transmit to the group
+        self.refresh_member(session_id).await;
+    }
+
+    /// Removes the client from the group, the group is removed once empty
+    pub async fn leave(&self, id: u32, session_id: u32) -> bool {
+        let Some(group) = self.groups.get_async(&id).await.map(|group| group.get().clone()) else {
+            return false;
+        };
+
+        let removed = group.members.remove_async(&session_id).await.is_some();
+
+        self.groups.remove_if_async(&id, |group| group.members.is_empty()).await;
+
+        if removed {
+            self.refresh_group(&group).await;
+            self.refresh_member(session_id).await;
+        }
+
+        removed
+    }
+
+    /// Removes the client from every group, used when the client disconnects
+    pub async fn remove_client(&self, session_id: u32) {
+        for id in self.get_client_groups(session_id).await {
+            self.leave(id, session_id).await;
+        }
+    }
+
+    /// Rebuilds the receivers of the groups of the client, after it started or stopped hearing
+    pub async fn refresh_client(&self, session_id: u32) {
+        for id in self.get_client_groups(session_id).await {
+            if let Some(group) = self.groups.get_async(&id).await.map(|group| group.get().clone()) {
+                self.refresh_group(&group).await;
+            }
+        }
+    }
+
+    /// Returns the members which should receive the audio of this client
+    pub async fn get_recipients(&self, session_id: u32) -> Option<Arc<Vec<ClientRef>>> {
+        self.recipients.read_async(&session_id, |_, recipients| recipients.get()).await
+    }
+
+    async fn get_client_groups(&self, session_id: u32) -> Vec<u32> {
+        let mut ids = Vec::new();
+
+        self.groups
+            .scan_async(|id, group| {
+                if group.members.contains(&session_id) {
+                    ids.push(*id);
+                }
+            })
+            .await;
+
+        ids
+    }
+
+    /// Rebuilds the receivers of the group, and the recipients of its transmitting members
+    async fn refresh_group(&self, group: &VoiceGroup) {
+        let generation = group.receivers.start_refresh();
+        let mut receivers = Vec::new();
+        let mut transmitters = Vec::new();
+
+        group
+            .members
+            .scan_async(|session_id, member| {
+                if member.receive && member.client.can_hear() {
+                    receivers.push(Arc::clone(&member.client));
+                }
+
+                if member.transmit {
+                    transmitters.push(*session_id);
+                }
+            })
+            .await;
+
+        group.receivers.set(generation, receivers);
+
+        for session_id in transmitters {
+            self.refresh_member(session_id).await;
+        }
+    }
+
+    /// Rebuilds the recipients of the member from the receivers of every group it transmits to
+    async fn refresh_member(&self, session_id: u32) {
+        // serialized since the entry is removed when empty, a concurrent refresh could otherwise
+        // store an older list after it
+        let _refresh = self.member_refresh.lock().await;
+        let mut recipients = std::collections::HashMap::new();
+
+        self.groups
+            .scan_async(|_, group| {
+                let can_transmit = group.members.read(&session_id, |_, member| member.transmit);
+
+                if can_transmit == Some(true) {
+                    for client in group.receivers.get().iter() {
+                        recipients.insert(client.session_id, Arc::clone(client));
+                    }
+                }
+            })
+            .await;
+
+        if recipients.is_empty() {
+            self.recipients.remove_async(&session_id).await;
+        } else {
+            let entry = self.recipients.entry_async(session_id).await.or_default();
+            let generation = entry.get().start_refresh();
+            entry.get().set(generation, recipients.into_values().collect());
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::client::ClientRef;
+use crate::crypt::ResyncCause;
 use crate::error::MumbleError;
 use crate::handler::Handler;
 use crate::proto::mumble::CryptSetup;
@@ -7,9 +8,18 @@ use crate::state::ServerStateRef;
 impl Handler for CryptSetup {
     async fn handle(&self, _state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
         if self.has_client_nonce() {
-            client.crypt_state.lock().await.set_decrypt_nonce(self.get_client_nonce());
+            // like murmur, an invalid nonce is ignored and the client will ask again
+            if let Err(e) = client.crypt_state.lock().set_decrypt_nonce(self.get_client_nonce()) {
+                tracing::warn!("{} sent an invalid crypt setup: {}", client, e);
+
+                return Ok(());
+            }
+
             Ok(())
         } else {
+            // the client failed to decrypt our packets and asks for a new nonce
+            client.record_resync(ResyncCause::ClientRequest);
+
             client.send_crypt_setup(false).await
         }
     }
@@ -4,6 +4,7 @@ mod crypt_setup;
 mod permission_query;
 mod ping;
 mod user_state;
+mod user_stats;
 mod version;
 mod voice_packet;
 mod voice_target;
@@ -14,15 +15,16 @@ use crate::client::ClientRef;
 use crate::error::MumbleError;
 use crate::message::ClientMessage;
 use crate::proto::mumble;
-use crate::proto::MessageKind;
+use crate::proto::{read_payload, MessageKind};
 use crate::state::ServerStateRef;
-use crate::voice::{decode_voice_packet, ServerBound};
+use crate::voice::ServerBound;
 use anyhow::Context;
-use bytes::BytesMut;
+use bytes::Bytes;
 use protobuf::Message;
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::sync::mpsc::Receiver;
 
+// only implemented and awaited inside the server, callers never need a Send bound on the futures
+#[allow(async_fn_in_trait)]
 pub trait Handler {
     async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError>;
 }
@@ -39,28 +41,16 @@ impl MessageHandler {
         Ok(())
     }
 
-    pub async fn handle<S: AsyncRead + Unpin>(
-        stream: &mut S,
-        consumer: &mut Receiver<ClientMessage>,
-        state: &ServerStateRef,
-        client: &ClientRef,
-    ) -> Result<(), anyhow::Error> {
+    pub async fn handle<S: AsyncRead + Unpin>(stream: &mut S, state: &ServerStateRef, client: &ClientRef) -> Result<(), anyhow::Error> {
         tokio::select! {
             kind_read = stream.read_u16() => {
-                let kind = kind_read?;
-                let size = stream.read_u32().await?;
-                // We'll just log this for now
-                // if size > 1024 {
-                //     return Err(anyhow!("Packet was size was {size}, max allowed is 1024"));
-                // }
-                let mut buf = BytesMut::zeroed(size as usize);
-                stream.read_exact(&mut buf).await?;
-
-                let message_kind = MessageKind::try_from(kind)?;
-
-                if size > 1024 {
-                    tracing::warn!("{} is reading a packet that is very large, got size {}, max expected 1024! MessageKind: {message_kind}", client, size);
-                }
+                let message_kind = MessageKind::try_from(kind_read?)?;
+                // oversized frames are rejected before allocating them, the client gets disconnected
+                let mut buf = read_payload(message_kind, stream, &state.config.tcp).await.map_err(|e| {
+                    tracing::warn!("disconnecting {}: {}", client, e);
+
+                    e
+                })?;
 
                 crate::metrics::MESSAGES_TOTAL.with_label_values(&["tcp", "input", message_kind.to_string().as_str()]).inc();
                 crate::metrics::MESSAGES_BYTES.with_label_values(&["tcp", "input", message_kind.to_string().as_str()]).inc_by(buf.len() as u64);
@@ -68,7 +58,9 @@ impl MessageHandler {
                 match message_kind {
                     MessageKind::Version => Self::try_handle::<mumble::Version>(&buf, state, client).await.context("kind: Version"),
                     MessageKind::UDPTunnel => {
-                        let voice_packet = match decode_voice_packet::<ServerBound>(&mut buf) {
+                        client.record_voice(buf.len());
+
+                        let voice_packet = match client.voice_protocol.decode::<ServerBound>(&mut buf) {
                             Ok(voice_packet) => voice_packet,
                             Err(e) => {
                                 tracing::error!("error decoding voice packet: {}", e);
@@ -88,6 +80,7 @@ impl MessageHandler {
                     MessageKind::PermissionQuery => Self::try_handle::<mumble::PermissionQuery>(&buf, state, client).await.context("kind: PermissionQuery"),
                     MessageKind::UserState => Self::try_handle::<mumble::UserState>(&buf, state, client).await.context("kind: UserState"),
                     MessageKind::VoiceTarget => Self::try_handle::<mumble::VoiceTarget>(&buf, state, client).await.context("kind: VoiceTarget"),
+                    MessageKind::UserStats => Self::try_handle::<mumble::UserStats>(&buf, state, client).await.context("kind: UserStats"),
                     _ => {
                         tracing::warn!("unsupported message kind: {:?}", message_kind);
 
@@ -95,7 +88,7 @@ impl MessageHandler {
                     }
                 }
             },
-            consume = consumer.recv() => {
+            consume = client.publisher.recv() => {
                 match consume {
                     Some(ClientMessage::RouteVoicePacket(packet)) => {
                         packet.handle(state, client).await.context("handle voice packet")
@@ -104,16 +97,16 @@ impl MessageHandler {
                         client.send_voice_packet(packet).await.context("send voice packet")
                     },
                     Some(ClientMessage::SendMessage { kind, payload }) => {
-                        client.send(payload.as_ref()).await.context(format!("send message of type: {}", kind))
+                        client.send(Bytes::clone(&payload)).context(format!("send message of type: {}", kind))
                     },
-                    Some(ClientMessage::Disconnect) => {
+                    Some(ClientMessage::Disconnect) | None => {
                         Err(MumbleError::ForceDisconnect).context("force disconnect")
                     },
-                    _ => {
-                        Ok(())
-                    }
                 }
             },
+            _ = client.writer.closed() => {
+                Err(MumbleError::WriterClosed).context("tcp writer closed")
+            },
         }
     }
 }
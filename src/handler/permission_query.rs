@@ -10,8 +10,8 @@ use crate::state::ServerStateRef;
 const PERM_TRAVERSE: u32 = 0x2;
 const PERM_ENTER: u32 = 0x4;
 const PERM_SPEAK: u32 = 0x8;
-const PERM_MUTEDEAFEN: u32 = 0x10;
-const PERM_MOVE: u32 = 0x20;
+pub const PERM_MUTEDEAFEN: u32 = 0x10;
+pub const PERM_MOVE: u32 = 0x20;
 // const PERM_MAKECHANNEL: u32 = 0x40;
 // const PERM_LINKCHANNEL: u32 = 0x80;
 const PERM_WHISPER: u32 = 0x100;
@@ -29,10 +29,15 @@ const PERM_DEFAULT: u32 = PERM_TRAVERSE | PERM_ENTER | PERM_SPEAK | PERM_WHISPER
 const PERM_ADMIN: u32 = PERM_DEFAULT | PERM_MUTEDEAFEN | PERM_MOVE | PERM_KICK | PERM_BAN;
 
 impl Handler for PermissionQuery {
-    async fn handle(&self, _state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
+    async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
         let mut pq = PermissionQuery::new();
         pq.set_channel_id(self.get_channel_id());
-        pq.set_permissions(PERM_ADMIN);
+
+        if client.is_admin(&state.config) {
+            pq.set_permissions(PERM_ADMIN);
+        } else {
+            pq.set_permissions(PERM_DEFAULT);
+        }
 
         {
             client.send_message(MessageKind::PermissionQuery, &pq).await?;
@@ -4,10 +4,11 @@ use crate::handler::Handler;
 use crate::proto::mumble::Ping;
 use crate::proto::MessageKind;
 use crate::state::ServerStateRef;
+use crate::udp_health::Transport;
 use std::time::Instant;
 
 impl Handler for Ping {
-    async fn handle(&self, _state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
+    async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
         let mut ping = Ping::default();
         ping.set_timestamp(self.get_timestamp());
 
@@ -16,11 +17,51 @@ impl Handler for Ping {
         }
 
         {
-            let crypt_state_read = client.crypt_state.lock().await;
+            let mut guard = client.ping_stats.lock();
+            let stats = &mut *guard;
+            stats.good = self.get_good();
+            stats.late = self.get_late();
+            stats.lost = self.get_lost();
+            stats.resync = self.get_resync();
+            stats.udp_packets = self.get_udp_packets();
+            stats.tcp_packets = self.get_tcp_packets();
+            stats.udp_ping_avg = self.get_udp_ping_avg();
+            stats.udp_ping_var = self.get_udp_ping_var();
+            stats.tcp_ping_avg = self.get_tcp_ping_avg();
+            stats.tcp_ping_var = self.get_tcp_ping_var();
+
+            for (transport, rtt, avg, count) in [
+                ("tcp", &mut stats.tcp_rtt, self.get_tcp_ping_avg(), self.get_tcp_packets()),
+                ("udp", &mut stats.udp_rtt, self.get_udp_ping_avg(), self.get_udp_packets()),
+            ] {
+                if let Some(sample) = rtt.record(avg, count) {
+                    crate::metrics::PING_RTT.with_label_values(&[transport]).observe(sample as f64);
+                    crate::metrics::PING_JITTER
+                        .with_label_values(&[transport])
+                        .observe(rtt.jitter as f64);
+                }
+            }
+        }
+
+        let sent = {
+            let crypt_state_read = client.crypt_state.lock();
             ping.set_good(crypt_state_read.good);
             ping.set_late(crypt_state_read.late);
             ping.set_lost(crypt_state_read.lost);
             ping.set_resync(crypt_state_read.resync);
+
+            crypt_state_read.sent
+        };
+
+        // clients which don't report their stats can't be checked
+        if state.config.udp_fallback.enabled && self.has_good() && client.udp_socket_addr.load().is_some() {
+            if let Some(transport) = client.udp_health.report(sent, self.get_good(), &state.config.udp_fallback) {
+                tracing::info!("{} voice packets now use {}", client, transport.as_str());
+
+                if transport == Transport::Fallback {
+                    crate::metrics::UDP_FALLBACKS_TOTAL.inc();
+                }
+            }
         }
 
         client.send_message(MessageKind::Ping, &ping).await
@@ -2,37 +2,137 @@ use std::sync::Arc;
 
 use crate::client::ClientRef;
 use crate::error::MumbleError;
+use crate::handler::permission_query::{PERM_MOVE, PERM_MUTEDEAFEN};
 use crate::handler::Handler;
-use crate::proto::mumble::UserState;
+use crate::proto::mumble::{PermissionDenied, PermissionDenied_DenyType, UserState};
+use crate::proto::MessageKind;
 use crate::state::ServerStateRef;
 
+/// Maximum length of the comment of a user, in bytes
+const MAX_COMMENT_LENGTH: usize = 5000;
+
 impl Handler for UserState {
     async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
         let session_id = { client.session_id };
 
-        if self.get_session() != session_id {
-            return Ok(());
+        let target = if !self.has_session() || self.get_session() == session_id {
+            Arc::clone(client)
+        } else {
+            match state.clients.get_async(&self.get_session()).await {
+                Some(target) => Arc::clone(target.get()),
+                None => return Ok(()),
+            }
+        };
+
+        let is_self = target.session_id == session_id;
+
+        // moderation of any user, including itself, is reserved to the admins
+        let is_admin = client.is_admin(&state.config);
+
+        if !is_admin {
+            if self.has_mute() || self.has_deaf() || self.has_suppress() || self.has_priority_speaker() {
+                return send_permission_denied(
+                    client,
+                    target.session_id,
+                    PermissionDenied_DenyType::Permission,
+                    Some(PERM_MUTEDEAFEN),
+                )
+                .await;
+            }
+
+            if !is_self && self.has_channel_id() {
+                return send_permission_denied(client, target.session_id, PermissionDenied_DenyType::Permission, Some(PERM_MOVE)).await;
+            }
+        }
+
+        // the other fields can only be changed by the user itself
+        let self_only = self.has_self_mute()
+            || self.has_self_deaf()
+            || self.has_recording()
+            || self.has_comment()
+            || self.has_plugin_context()
+            || self.has_plugin_identity()
+            || !self.get_listening_channel_add().is_empty()
+            || !self.get_listening_channel_remove().is_empty();
+
+        if !is_self && self_only {
+            return send_permission_denied(client, target.session_id, PermissionDenied_DenyType::Permission, None).await;
         }
 
-        client.update(self);
+        if self.get_comment().len() > MAX_COMMENT_LENGTH {
+            return send_permission_denied(client, target.session_id, PermissionDenied_DenyType::TextTooLong, None).await;
+        }
+
+        let could_hear = target.can_hear();
+
+        let mut changes = target.update(self, is_admin).unwrap_or_else(|| {
+            let mut user_state = UserState::new();
+            user_state.set_session(target.session_id);
+
+            user_state
+        });
+
+        changes.set_actor(session_id);
+
+        if target.can_hear() != could_hear {
+            state.refresh_client(&target).await;
+        }
 
         if self.has_channel_id() {
-            state.set_client_channel(client, self.get_channel_id()).await?;
+            state.set_client_channel(&target, self.get_channel_id()).await?;
         }
 
         for channel_id in self.get_listening_channel_add() {
-            if let Some(channel) = state.channels.get_async(channel_id).await {
+            if let Some(channel) = state.channels.get_async(channel_id).await.map(|channel| Arc::clone(channel.get())) {
                 // if this errors it means our client is already in it, we can just ignore.
-                let _ = channel.listeners.insert_async(session_id, Arc::clone(client)).await;
+                if channel.listeners.insert_async(session_id, Arc::clone(client)).await.is_ok() {
+                    state.refresh_channel(&channel).await;
+                    changes.mut_listening_channel_add().push(*channel_id);
+                }
             }
         }
 
         for channel_id in self.get_listening_channel_remove() {
-            if let Some(channel) = state.channels.get_async(channel_id).await {
-                channel.listeners.remove_async(&session_id).await;
+            if let Some(channel) = state.channels.get_async(channel_id).await.map(|channel| Arc::clone(channel.get())) {
+                if channel.listeners.remove_async(&session_id).await.is_some() {
+                    state.refresh_channel(&channel).await;
+                    changes.mut_listening_channel_remove().push(*channel_id);
+                }
             }
         }
 
+        let has_changes = changes.has_mute()
+            || changes.has_self_mute()
+            || changes.has_suppress()
+            || changes.has_priority_speaker()
+            || changes.has_recording()
+            || changes.has_comment()
+            || !changes.get_listening_channel_add().is_empty()
+            || !changes.get_listening_channel_remove().is_empty();
+
+        if has_changes {
+            state.broadcast_message(MessageKind::UserState, &changes)?;
+        }
+
         Ok(())
     }
 }
+
+async fn send_permission_denied(
+    client: &ClientRef,
+    session_id: u32,
+    deny_type: PermissionDenied_DenyType,
+    permission: Option<u32>,
+) -> Result<(), MumbleError> {
+    tracing::warn!("{} user state change of session {} denied: {:?}", client, session_id, deny_type);
+
+    let mut permission_denied = PermissionDenied::new();
+    permission_denied.set_session(session_id);
+    permission_denied.set_field_type(deny_type);
+
+    if let Some(permission) = permission {
+        permission_denied.set_permission(permission);
+    }
+
+    client.send_message(MessageKind::PermissionDenied, &permission_denied).await
+}
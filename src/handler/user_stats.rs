@@ -0,0 +1,101 @@
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::client::{ClientRef, RttStats};
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::proto::mumble::{UserStats, UserStats_Stats};
+use crate::proto::MessageKind;
+use crate::state::ServerStateRef;
+
+impl Handler for UserStats {
+    async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
+        let target = if !self.has_session() || self.get_session() == client.session_id {
+            Arc::clone(client)
+        } else {
+            match state.clients.get_async(&self.get_session()).await {
+                Some(target) => Arc::clone(target.get()),
+                None => return Ok(()),
+            }
+        };
+
+        // like murmur, the details (version, address...) are reserved to the user itself and the
+        // admins, the connection statistics to the users of the same channel
+        let extend = target.session_id == client.session_id || client.is_admin(&state.config);
+        let local = extend || target.channel_id.load(Ordering::Relaxed) == client.channel_id.load(Ordering::Relaxed);
+        let details = extend && !self.get_stats_only();
+
+        let mut user_stats = UserStats::new();
+        user_stats.set_session(target.session_id);
+        user_stats.set_stats_only(self.get_stats_only());
+
+        if local {
+            let from_client = {
+                let crypt = target.crypt_state.lock();
+
+                let mut stats = UserStats_Stats::new();
+                stats.set_good(crypt.good);
+                stats.set_late(crypt.late);
+                stats.set_lost(crypt.lost);
+                stats.set_resync(crypt.resync);
+
+                stats
+            };
+
+            let ping_stats = *target.ping_stats.lock();
+
+            let mut from_server = UserStats_Stats::new();
+            from_server.set_good(ping_stats.good);
+            from_server.set_late(ping_stats.late);
+            from_server.set_lost(ping_stats.lost);
+            from_server.set_resync(ping_stats.resync);
+
+            user_stats.set_from_client(from_client);
+            user_stats.set_from_server(from_server);
+            user_stats.set_udp_packets(ping_stats.udp_packets);
+            user_stats.set_tcp_packets(ping_stats.tcp_packets);
+
+            // the rolling statistics follow the recent pings, the ones reported by the client
+            // cover its whole connection
+            let (udp_ping_avg, udp_ping_var) = rolling_or_reported(&ping_stats.udp_rtt, ping_stats.udp_ping_avg, ping_stats.udp_ping_var);
+            let (tcp_ping_avg, tcp_ping_var) = rolling_or_reported(&ping_stats.tcp_rtt, ping_stats.tcp_ping_avg, ping_stats.tcp_ping_var);
+
+            user_stats.set_udp_ping_avg(udp_ping_avg);
+            user_stats.set_udp_ping_var(udp_ping_var);
+            user_stats.set_tcp_ping_avg(tcp_ping_avg);
+            user_stats.set_tcp_ping_var(tcp_ping_var);
+            user_stats.set_bandwidth(target.get_bandwidth());
+        }
+
+        let now = Instant::now();
+        user_stats.set_onlinesecs(now.duration_since(target.connected_at).as_secs() as u32);
+        user_stats.set_idlesecs(now.duration_since(target.last_voice.load()).as_secs() as u32);
+
+        if details {
+            user_stats.set_version(target.version.clone());
+            user_stats.set_celt_versions(target.codecs.clone());
+            user_stats.set_opus(target.authenticate.get_opus());
+
+            // the address is always sent as an ipv6 one, ipv4 addresses are mapped
+            let address = match target.peer_ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+
+            user_stats.set_address(address.octets().to_vec());
+        }
+
+        client.send_message(MessageKind::UserStats, &user_stats).await
+    }
+}
+
+/// Returns the mean and the variance of the round trip time
+fn rolling_or_reported(rtt: &RttStats, reported_avg: f32, reported_var: f32) -> (f32, f32) {
+    if rtt.samples == 0 {
+        return (reported_avg, reported_var);
+    }
+
+    (rtt.rtt, rtt.jitter * rtt.jitter)
+}
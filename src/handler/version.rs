@@ -5,7 +5,11 @@ use crate::proto::mumble::Version;
 use crate::state::ServerStateRef;
 
 impl Handler for Version {
-    async fn handle(&self, _state: &ServerStateRef, _client: &ClientRef) -> Result<(), MumbleError> {
+    async fn handle(&self, _state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
+        // the voice protocol is negotiated from the version received during the handshake, later
+        // changes are ignored
+        tracing::debug!("ignoring version {:?} sent by {} after its handshake", self.get_release(), client);
+
         Ok(())
     }
 }
@@ -1,87 +1,64 @@
-use scc::HashMap;
-
 use crate::client::ClientRef;
+use crate::config::{PositionSource, PriorityMode};
 use crate::error::MumbleError;
 use crate::message::ClientMessage;
 use crate::state::ServerStateRef;
-use crate::voice::{ClientBound, VoicePacket};
+use crate::voice::{read_position, ClientBound, SharedVoicePacket, VoicePacket};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::Handler;
 
 impl Handler for VoicePacket<ClientBound> {
     async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
-        let mute = client.is_muted();
-
-        if mute {
+        if !client.can_speak() {
             return Ok(());
         }
 
-        if let VoicePacket::<ClientBound>::Audio { target, session_id, .. } = self {
-            // copy the data into an arc so we can reuse the packet for each client
-            let packet = Arc::new(self.clone());
-
-            let listening_clients: HashMap<u32, ClientRef> = HashMap::new();
+        if let VoicePacket::<ClientBound>::Audio {
+            target,
+            session_id,
+            position_info,
+            ..
+        } = self
+        {
+            // copy the data into an arc so we can reuse the packet (and its encoding) for each client
+            let packet = Arc::new(SharedVoicePacket::new(self.clone()));
+
+            if state.config.proximity.source == PositionSource::Client {
+                if let Some(position) = position_info.as_deref().and_then(read_position) {
+                    client.position.store(Some(position));
+                }
+            }
 
-            match *target {
+            let position = client.position.load();
+            let bucket = client.get_bucket();
+            let cross_bucket = state.config.buckets.cross_bucket_targets.contains(target);
+            // only set for channel audio when proximity is enabled for the channel
+            let mut proximity_radius = None;
+
+            // the recipients are precomputed when clients join, leave, listen, deafen or change their
+            // targets, routing is only a lookup here
+            let recipients = match *target {
+                // Radio frequencies and calls, managed by the server
+                target if state.config.radio.target == Some(target) => state.radios.get_recipients(client.session_id).await,
+                target if state.config.call.target == Some(target) => state.calls.get_recipients(client.session_id).await,
                 // Channel
                 0 => {
                     let channel_id = client.channel_id.load(Ordering::Relaxed);
-                    let channel_result = state.channels.get_async(&channel_id).await;
 
-                    if let Some(channel) = channel_result {
-                        let mut iter = channel.clients.first_entry_async().await;
+                    state
+                        .channels
+                        .read_async(&channel_id, |_, channel| {
+                            proximity_radius = state.config.proximity.get_radius(&channel.name);
 
-                        while let Some(client) = iter {
-                            let cl = client.get();
-                            listening_clients.upsert_async(cl.session_id, Arc::clone(cl)).await;
-
-                            iter = client.next_async().await;
-                        }
-                    }
+                            channel.recipients.get()
+                        })
+                        .await
                 }
                 // Voice target (whisper)
-                1..=30 => {
-                    let target = client.get_target(*target);
-
-                    if let Some(target) = target {
-                        let mut iter = target.sessions.first_entry_async().await;
-
-                        while let Some(client) = iter {
-                            if let Some(cl) = state.clients.get_async(client.key()).await {
-                                listening_clients.upsert_async(cl.session_id, Arc::clone(cl.get())).await;
-                            }
-
-                            iter = client.next_async().await;
-                        }
-
-                        let mut iter = target.channels.first_entry_async().await;
-
-                        while let Some(channel) = iter {
-                            if let Some(ch) = state.channels.get_async(channel.key()).await {
-                                let mut listen_iter = ch.listeners.first_entry_async().await;
-                                while let Some(listen_cl) = listen_iter {
-                                    listening_clients
-                                        .upsert_async(listen_cl.session_id, Arc::clone(listen_cl.get()))
-                                        .await;
-
-                                    listen_iter = listen_cl.next_async().await;
-                                }
-
-                                let mut client_iter = ch.clients.first_entry_async().await;
-                                while let Some(client_cl) = client_iter {
-                                    listening_clients
-                                        .upsert_async(client_cl.session_id, Arc::clone(client_cl.get()))
-                                        .await;
-
-                                    client_iter = client_cl.next_async().await;
-                                }
-                            }
-                            iter = channel.next_async().await;
-                        }
-                    }
-                }
+                1..=30 => client.get_target(*target).map(|target| target.recipients.get()),
                 // Loopback
                 31 => {
                     client.send_voice_packet(Arc::clone(&packet)).await?;
@@ -90,25 +67,73 @@ impl Handler for VoicePacket<ClientBound> {
                 }
                 _ => {
                     tracing::error!("invalid voice target: {}", *target);
+
+                    None
+                }
+            };
+
+            let Some(recipients) = recipients else {
+                return Ok(());
+            };
+
+            let priority_config = &state.config.priority_speaker;
+            let is_priority_speaker = client.is_priority_speaker();
+            let now = Instant::now();
+            // only created when needed, shared by all the ducked listeners
+            let mut ducked_packet: Option<Arc<SharedVoicePacket>> = None;
+
+            for cl in recipients.iter() {
+                // the speaker never receives its own audio, except with the loopback target
+                if cl.session_id == *session_id {
+                    continue;
                 }
-            }
 
-            // remove the calling client from the session list so we don't have to branch here.
-            listening_clients.remove_async(session_id).await;
+                // players in another instance of the game never hear each other, unless the
+                // target is allowed to cross buckets
+                if !cross_bucket && cl.get_bucket() != bucket {
+                    continue;
+                }
 
-            listening_clients.scan_async(|_k, cl| {
-                if cl.is_deaf() {
-                    return;
+                if let Some(radius) = proximity_radius {
+                    if !cl.is_in_range(position, radius) {
+                        continue;
+                    }
                 }
 
-                match cl.publisher.try_send(ClientMessage::SendVoicePacket(Arc::clone(&packet))) {
+                let mut packet = &packet;
+
+                if priority_config.mode != PriorityMode::Off {
+                    if is_priority_speaker {
+                        cl.priority_hold_until.store(now + Duration::from_millis(priority_config.hold_ms));
+                    } else if cl.priority_hold_until.load() > now {
+                        if priority_config.mode == PriorityMode::Suppress {
+                            continue;
+                        }
+
+                        packet = ducked_packet.get_or_insert_with(|| {
+                            let mut ducked = packet.packet.clone();
+                            if let VoicePacket::Audio { volume_adjustment, .. } = &mut ducked {
+                                *volume_adjustment = Some(priority_config.duck_volume);
+                            }
+
+                            Arc::new(SharedVoicePacket::new(ducked))
+                        });
+                    }
+                }
+
+                // the packet is translated to the format of each client when sent, but CELT and
+                // Speex audio can't be sent to clients using the protobuf format
+                if !cl.voice_protocol.supports(&packet.packet) {
+                    continue;
+                }
+
+                match cl.publisher.send(ClientMessage::SendVoicePacket(Arc::clone(packet))) {
                     Ok(_) => {}
                     Err(err) => {
                         tracing::error!("error sending voice packet message to {}: {}", cl, err);
                     }
                 }
-
-            }).await;
+            }
         }
 
         Ok(())
@@ -5,7 +5,7 @@ use crate::proto::mumble::VoiceTarget;
 use crate::state::ServerStateRef;
 
 impl Handler for VoiceTarget {
-    async fn handle(&self, _: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
+    async fn handle(&self, state: &ServerStateRef, client: &ClientRef) -> Result<(), MumbleError> {
         // mumble spec limits the usable voice targets to 1..=30
         if self.get_id() < 1 || self.get_id() >= 31 {
             tracing::error!("invalid voice target id: {}", self.get_id());
@@ -28,6 +28,10 @@ impl Handler for VoiceTarget {
             }
         };
 
+        let target_id = self.get_id() as u8;
+
+        state.unregister_target(client.session_id, target_id, &target).await;
+
         target.sessions.clear_async().await;
         target.channels.clear_async().await;
 
@@ -45,6 +49,9 @@ impl Handler for VoiceTarget {
             }
         }
 
+        state.register_target(client.session_id, target_id, &target).await;
+        state.refresh_target(client.session_id, target_id).await;
+
         Ok(())
     }
 }
@@ -0,0 +1,37 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::AppStateRef;
+
+#[derive(Serialize, Deserialize)]
+pub struct Bucket {
+    bucket: u32,
+    user: String,
+}
+
+pub async fn post_bucket(State(state): State<AppStateRef>, Json(bucket): Json<Bucket>) -> StatusCode {
+    if let Some(client) = state.server.get_client_by_name(bucket.user.as_str()).await {
+        client.set_bucket(bucket.bucket);
+
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+pub async fn get_bucket(Path(username): Path<String>, State(state): State<AppStateRef>) -> Result<Json<Bucket>, StatusCode> {
+    if let Some(client) = state.server.get_client_by_name(username.as_str()).await {
+        let bucket = Bucket {
+            bucket: client.get_bucket(),
+            user: username,
+        };
+
+        return Ok(Json(bucket));
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
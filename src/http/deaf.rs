@@ -6,6 +6,7 @@ use axum::{
 use serde::{Deserialize, Serialize};
 
 use super::AppStateRef;
+use crate::proto::mumble::UserState;
 
 #[derive(Serialize, Deserialize)]
 pub struct Deaf {
@@ -19,7 +20,11 @@ pub async fn post_deaf(State(state): State<AppStateRef>, Json(deaf): Json<Deaf>)
 
     match client {
         Some(client) => {
-            client.set_deaf(deaf.deaf);
+            let mut user_state = UserState::new();
+            user_state.set_deaf(deaf.deaf);
+
+            // deafening also mutes the client
+            state.server.update_user_state(&client, &user_state).await;
 
             StatusCode::OK
         }
@@ -0,0 +1,106 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::AppStateRef;
+use crate::group::VoiceGroups;
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupMembership {
+    /// Radio frequency or call id
+    id: u32,
+    user: String,
+    #[serde(default = "default_true")]
+    transmit: bool,
+    #[serde(default = "default_true")]
+    receive: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupLeave {
+    id: u32,
+    user: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupMemberStatus {
+    user: String,
+    session_id: u32,
+    transmit: bool,
+    receive: bool,
+}
+
+async fn join(groups: &VoiceGroups, state: &AppStateRef, membership: GroupMembership) -> StatusCode {
+    match state.server.get_client_by_name(membership.user.as_str()).await {
+        Some(client) => {
+            groups.join(membership.id, client, membership.transmit, membership.receive).await;
+
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn leave(groups: &VoiceGroups, state: &AppStateRef, leave: GroupLeave) -> StatusCode {
+    let Some(client) = state.server.get_client_by_name(leave.user.as_str()).await else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if groups.leave(leave.id, client.session_id).await {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn status(groups: &VoiceGroups) -> Json<HashMap<u32, Vec<GroupMemberStatus>>> {
+    let mut status = HashMap::new();
+    let mut iter = groups.groups.first_entry_async().await;
+
+    while let Some(group) = iter {
+        let mut members = Vec::new();
+
+        group.members.scan(|session_id, member| {
+            members.push(GroupMemberStatus {
+                user: member.client.get_name().as_ref().clone(),
+                session_id: *session_id,
+                transmit: member.transmit,
+                receive: member.receive,
+            });
+        });
+
+        status.insert(*group.key(), members);
+
+        iter = group.next_async().await;
+    }
+
+    Json(status)
+}
+
+pub async fn post_radio(State(state): State<AppStateRef>, Json(membership): Json<GroupMembership>) -> StatusCode {
+    join(&state.server.radios, &state, membership).await
+}
+
+pub async fn delete_radio(State(state): State<AppStateRef>, Json(group_leave): Json<GroupLeave>) -> StatusCode {
+    leave(&state.server.radios, &state, group_leave).await
+}
+
+pub async fn get_radio(State(state): State<AppStateRef>) -> Json<HashMap<u32, Vec<GroupMemberStatus>>> {
+    status(&state.server.radios).await
+}
+
+pub async fn post_call(State(state): State<AppStateRef>, Json(membership): Json<GroupMembership>) -> StatusCode {
+    join(&state.server.calls, &state, membership).await
+}
+
+pub async fn delete_call(State(state): State<AppStateRef>, Json(group_leave): Json<GroupLeave>) -> StatusCode {
+    leave(&state.server.calls, &state, group_leave).await
+}
+
+pub async fn get_call(State(state): State<AppStateRef>) -> Json<HashMap<u32, Vec<GroupMemberStatus>>> {
+    status(&state.server.calls).await
+}
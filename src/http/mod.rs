@@ -1,7 +1,11 @@
 mod axum_auth_wrapper;
+mod bucket;
 mod deaf;
+mod group;
 mod metrics;
 mod mute;
+mod position;
+mod priority;
 mod status;
 
 use std::sync::Arc;
@@ -12,9 +16,13 @@ use axum::{
     Router,
 };
 use axum_auth_wrapper::auth_basic;
+use bucket::{get_bucket, post_bucket};
 use deaf::{get_deaf, post_deaf};
+use group::{delete_call, delete_radio, get_call, get_radio, post_call, post_radio};
 use metrics::get_metrics;
 use mute::{get_mute, post_mute};
+use position::{post_positions, post_positions_stream};
+use priority::{get_priority, post_priority};
 use status::get_status;
 
 use crate::state::ServerStateRef;
@@ -41,11 +49,19 @@ pub fn create_http_server(state: ServerStateRef, username: String, password: Opt
 
     Some(
         Router::new()
+            .route("/bucket", post(post_bucket))
+            .route("/bucket/:player_id", get(get_bucket))
+            .route("/call", get(get_call).post(post_call).delete(delete_call))
             .route("/deaf", post(post_deaf))
             .route("/deaf/:player_id", get(get_deaf))
             .route("/metrics", get(get_metrics))
             .route("/mute", post(post_mute))
             .route("/mute/:player_id", get(get_mute))
+            .route("/positions", post(post_positions))
+            .route("/positions/stream", post(post_positions_stream))
+            .route("/priority", post(post_priority))
+            .route("/priority/:player_id", get(get_priority))
+            .route("/radio", get(get_radio).post(post_radio).delete(delete_radio))
             .route("/status", get(get_status))
             .route_layer(from_fn_with_state(app_state.clone(), auth_basic))
             .with_state(app_state),
@@ -6,6 +6,7 @@ use axum::{
 use serde::{Deserialize, Serialize};
 
 use super::AppStateRef;
+use crate::proto::mumble::UserState;
 
 #[derive(Serialize, Deserialize)]
 pub struct Mute {
@@ -15,7 +16,11 @@ pub struct Mute {
 
 pub async fn post_mute(State(state): State<AppStateRef>, Json(mute): Json<Mute>) -> StatusCode {
     if let Some(client) = state.server.get_client_by_name(mute.user.as_str()).await {
-        client.set_mute(mute.mute);
+        let mut user_state = UserState::new();
+        user_state.set_mute(mute.mute);
+
+        // unmuting also undeafens the client
+        state.server.update_user_state(&client, &user_state).await;
 
         StatusCode::OK
     } else {
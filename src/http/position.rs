@@ -0,0 +1,108 @@
+use axum::{body::Body, extract::State, http::StatusCode, Json};
+use bytes::{Buf, BytesMut};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::AppStateRef;
+
+#[derive(Serialize, Deserialize)]
+pub struct Position {
+    user: String,
+    /// Routing bucket of the player, players in different buckets never hear each other
+    #[serde(default)]
+    bucket: u32,
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PositionLine {
+    Batch(Vec<Position>),
+    Single(Position),
+}
+
+#[derive(Serialize, Default)]
+pub struct PositionsUpdated {
+    updated: usize,
+    not_found: Vec<String>,
+}
+
+/// Maximum length of a line of the positions stream, requests sending a longer line are rejected
+const MAX_LINE_LENGTH: usize = 1 << 20;
+
+async fn update_positions(state: &AppStateRef, positions: Vec<Position>, result: &mut PositionsUpdated) {
+    for position in positions {
+        match state.server.get_client_by_name(&position.user).await {
+            Some(client) => {
+                client.set_bucket(position.bucket);
+                client.position.store(Some([position.x, position.y, position.z]));
+
+                result.updated += 1;
+            }
+            None => result.not_found.push(position.user),
+        }
+    }
+}
+
+pub async fn post_positions(State(state): State<AppStateRef>, Json(positions): Json<Vec<Position>>) -> Json<PositionsUpdated> {
+    let mut result = PositionsUpdated::default();
+
+    update_positions(&state, positions, &mut result).await;
+
+    Json(result)
+}
+
+/// Streaming variant of [post_positions], the body contains one json position or array of positions
+/// per line, each line is applied as soon as it is received so a single request can be kept open
+/// by the game server for the whole session.
+pub async fn post_positions_stream(State(state): State<AppStateRef>, body: Body) -> Result<Json<PositionsUpdated>, StatusCode> {
+    let mut result = PositionsUpdated::default();
+    let mut stream = body.into_data_stream();
+    let mut buffer = BytesMut::new();
+    // the bytes of the buffer already known not to contain a newline
+    let mut scanned = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            tracing::warn!("positions stream error: {}", e);
+
+            StatusCode::BAD_REQUEST
+        })?;
+
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(end) = buffer[scanned..].iter().position(|b| *b == b'\n') {
+            let line = buffer.split_to(scanned + end);
+            buffer.advance(1);
+            scanned = 0;
+
+            handle_position_line(&state, &line, &mut result).await;
+        }
+
+        scanned = buffer.len();
+
+        if buffer.len() > MAX_LINE_LENGTH {
+            tracing::warn!("positions stream line longer than {} bytes, closing the stream", MAX_LINE_LENGTH);
+
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    handle_position_line(&state, &buffer, &mut result).await;
+
+    Ok(Json(result))
+}
+
+async fn handle_position_line(state: &AppStateRef, line: &[u8], result: &mut PositionsUpdated) {
+    if line.trim_ascii().is_empty() {
+        return;
+    }
+
+    match serde_json::from_slice::<PositionLine>(line) {
+        Ok(PositionLine::Batch(positions)) => update_positions(state, positions, result).await,
+        Ok(PositionLine::Single(position)) => update_positions(state, vec![position], result).await,
+        Err(e) => tracing::warn!("invalid position in positions stream: {}", e),
+    }
+}
@@ -0,0 +1,41 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::AppStateRef;
+use crate::proto::mumble::UserState;
+
+#[derive(Serialize, Deserialize)]
+pub struct PrioritySpeaker {
+    priority_speaker: bool,
+    user: String,
+}
+
+pub async fn post_priority(State(state): State<AppStateRef>, Json(priority): Json<PrioritySpeaker>) -> StatusCode {
+    let Some(client) = state.server.get_client_by_name(priority.user.as_str()).await else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    let mut user_state = UserState::new();
+    user_state.set_priority_speaker(priority.priority_speaker);
+
+    state.server.update_user_state(&client, &user_state).await;
+
+    StatusCode::OK
+}
+
+pub async fn get_priority(Path(username): Path<String>, State(state): State<AppStateRef>) -> Result<Json<PrioritySpeaker>, StatusCode> {
+    if let Some(client) = state.server.get_client_by_name(username.as_str()).await {
+        let priority = PrioritySpeaker {
+            priority_speaker: client.is_priority_speaker(),
+            user: username,
+        };
+
+        return Ok(Json(priority));
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
@@ -6,6 +6,8 @@ use std::sync::atomic::Ordering;
 use std::time::Instant;
 
 use super::AppStateRef;
+use crate::crypt::ResyncCause;
+use crate::proto::format_version_v2;
 
 #[derive(Serialize, Deserialize)]
 pub struct MumbleClient {
@@ -13,11 +15,37 @@ pub struct MumbleClient {
     pub session_id: u32,
     pub channel: Option<String>,
     pub mute: bool,
+    pub deaf: bool,
+    pub suppress: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub bucket: u32,
+    /// How the voice packets reach the client: "udp", "tcp" or "fallback" (tcp while udp is broken)
+    pub transport: String,
+    pub version: String,
+    pub release: String,
+    pub os: String,
+    pub os_version: String,
     pub good: u32,
     pub late: u32,
     pub lost: u32,
     pub resync: u32,
+    /// Voice packets sent to the client, and the client view of the packets it received
+    pub sent: u32,
+    pub remote_good: u32,
+    pub remote_late: u32,
+    pub remote_lost: u32,
+    pub remote_resync: u32,
+    /// Resynchronizations of the crypt state by cause
+    pub resyncs: HashMap<String, u32>,
     pub last_good_duration: u128,
+    pub tcp_rtt: f32,
+    pub tcp_jitter: f32,
+    pub udp_rtt: f32,
+    pub udp_jitter: f32,
+    pub queue_control: usize,
+    pub queue_voice: usize,
+    pub queue_dropped: u64,
     pub targets: Vec<MumbleTarget>,
 }
 
@@ -44,21 +72,50 @@ pub async fn get_status(State(state): State<AppStateRef>) -> Json<HashMap<u32, M
         };
 
         {
-            let (good, late, lost, resync, last_good) = {
-                let crypt = client.crypt_state.lock().await;
-                (crypt.good, crypt.late, crypt.lost, crypt.resync, crypt.last_good)
+            let (good, late, lost, resync, sent, last_good) = {
+                let crypt = client.crypt_state.lock();
+                (crypt.good, crypt.late, crypt.lost, crypt.resync, crypt.sent, crypt.last_good)
             };
 
+            let (queue_control, queue_voice) = client.publisher.depth();
+            let ping_stats = *client.ping_stats.lock();
+
             let mut mumble_client = MumbleClient {
                 name: client.get_name().as_ref().clone(),
                 session_id: client.session_id,
                 channel: channel_name,
                 mute: client.is_muted(),
+                deaf: client.is_deaf(),
+                suppress: client.is_suppressed(),
+                self_mute: client.is_self_muted(),
+                self_deaf: client.is_self_deaf(),
+                bucket: client.get_bucket(),
+                transport: client.get_transport().as_str().to_string(),
+                version: format_version_v2(client.get_version_v2()),
+                release: client.version.get_release().to_string(),
+                os: client.version.get_os().to_string(),
+                os_version: client.version.get_os_version().to_string(),
                 good,
                 late,
                 lost,
                 resync,
+                sent,
+                remote_good: ping_stats.good,
+                remote_late: ping_stats.late,
+                remote_lost: ping_stats.lost,
+                remote_resync: ping_stats.resync,
+                resyncs: ResyncCause::ALL
+                    .iter()
+                    .map(|cause| (cause.as_str().to_string(), client.get_resyncs(*cause)))
+                    .collect(),
                 last_good_duration: Instant::now().duration_since(last_good).as_millis(),
+                tcp_rtt: ping_stats.tcp_rtt.rtt,
+                tcp_jitter: ping_stats.tcp_rtt.jitter,
+                udp_rtt: ping_stats.udp_rtt.rtt,
+                udp_jitter: ping_stats.udp_rtt.jitter,
+                queue_control,
+                queue_voice,
+                queue_dropped: client.publisher.voice_dropped.load(Ordering::Relaxed),
                 targets: Vec::new(),
             };
 
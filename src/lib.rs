@@ -0,0 +1,29 @@
+//! Zumble, a mumble server implementation for FiveM
+//!
+//! The server itself is the `zumble` binary, the modules are exposed so benchmarks and fuzz targets
+//! can use them.
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod channel;
+pub mod check;
+pub mod clean;
+pub mod client;
+pub mod config;
+pub mod crypt;
+pub mod error;
+pub mod group;
+mod handler;
+pub mod http;
+pub mod message;
+pub mod metrics;
+pub mod names;
+pub mod proto;
+pub mod recipients;
+pub mod server;
+pub mod state;
+pub mod target;
+pub mod udp_health;
+pub mod varint;
+pub mod voice;
@@ -1,30 +1,12 @@
 use rustls::ServerConfig;
 
-#[macro_use]
-extern crate lazy_static;
-
-mod channel;
-mod check;
-mod clean;
-mod client;
-mod crypt;
-mod error;
-mod handler;
-mod http;
-mod message;
-mod metrics;
-mod proto;
-mod server;
-mod state;
-mod target;
-mod varint;
-mod voice;
-
-use crate::clean::clean_loop;
-use crate::http::create_http_server;
-use crate::proto::mumble::Version;
-use crate::server::{create_tcp_server, create_udp_server};
-use crate::state::ServerState;
+use zumble::clean::clean_loop;
+use zumble::config::Config;
+use zumble::http::create_http_server;
+use zumble::proto::mumble::Version;
+use zumble::proto::{version_v1, version_v2};
+use zumble::server::{bind_udp_sockets, create_tcp_server, create_udp_server};
+use zumble::state::ServerState;
 
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
@@ -34,7 +16,7 @@ use rustls_pki_types::pem::PemObject;
 use rustls_pki_types::PrivateKeyDer;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::TcpListener;
 use tokio::task::JoinSet;
 use tokio_rustls::rustls::{self};
 use tokio_rustls::TlsAcceptor;
@@ -71,6 +53,9 @@ struct Args {
     /// Path to the certificate file for the TLS certificate
     #[clap(long, value_parser, default_value = "cert.pem")]
     cert: String,
+    /// Path to the server.toml configuration file, defaults are used when not provided
+    #[clap(short, long, value_parser, default_value = None)]
+    config: Option<String>,
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -82,42 +67,49 @@ async fn main() {
 
     let args = Args::parse();
 
+    let server_config = Config::load(args.config.as_deref()).expect("failed to load config");
+
     let config = Arc::new(generate_rustls_cert());
 
     let http_config = RustlsConfig::from_config(Arc::clone(&config));
 
     let acceptor = TlsAcceptor::from(Arc::clone(&config));
 
-    // ignore the fact that `0` does nothing here
-    #[allow(clippy::identity_op)]
-    // Simulate 1.4.0 protocol version
-    let version = 1 << 16 | 4 << 8 | 0;
+    // Announce the 1.5.0 protocol so clients supporting it use the protobuf voice protocol
+    let version = version_v1(1, 5, 0);
 
     let mut server_version = Version::new();
     server_version.set_os(std::env::consts::FAMILY.to_string());
     server_version.set_os_version(std::env::consts::OS.to_string());
     server_version.set_release(VERSION.to_string());
     server_version.set_version(version);
+    server_version.set_version_v2(version_v2(1, 5, 0));
 
     let mut set = JoinSet::new();
 
-    let std_socket = std::net::UdpSocket::bind(&args.listen).unwrap();
-    std_socket.set_nonblocking(true).unwrap();
-
-    let socket = UdpSocket::from_std(std_socket).unwrap();
+    let listen_addr: SocketAddr = args.listen.parse().expect("Got invalid data for 'listen', it was not a usable ip");
 
-    let udp_socket = Arc::new(socket);
+    let udp_workers = server_config.get_udp_workers();
+    let udp_sockets = bind_udp_sockets(listen_addr, udp_workers).expect("failed to bind to udp address");
 
-    let state = Arc::new(ServerState::new(udp_socket.clone()));
-    let udp_state = state.clone();
+    let state = Arc::new(ServerState::new(server_config, Arc::clone(&udp_sockets[0])));
 
-    tracing::info!("tcp/udp server start listening on {}", args.listen);
+    tracing::info!(
+        "tcp/udp server start listening on {} with {} udp workers",
+        args.listen,
+        udp_sockets.len()
+    );
 
     let cancelation_token = CancellationToken::new();
 
-    set.spawn(async move {
-        create_udp_server(version, udp_socket, udp_state, cancelation_token.clone()).await;
-    });
+    for udp_socket in udp_sockets {
+        let udp_state = state.clone();
+        let udp_cancelation_token = cancelation_token.clone();
+
+        set.spawn(async move {
+            create_udp_server(version, udp_socket, udp_state, udp_cancelation_token).await;
+        });
+    }
 
     let clean_state = state.clone();
 
@@ -125,9 +117,7 @@ async fn main() {
         clean_loop(clean_state).await;
     });
 
-    let tcp_addr: SocketAddr = args.listen.parse().expect("Got invalid data for 'listen', it was not a usable ip");
-
-    let tcp_listener = TcpListener::bind(tcp_addr).await.expect("failed to bind to tcp address");
+    let tcp_listener = TcpListener::bind(listen_addr).await.expect("failed to bind to tcp address");
     let tcp_state = state.clone();
     // Create tcp server
     set.spawn(async move {
@@ -158,9 +148,7 @@ async fn main() {
         tracing::info!("http server not started, no auth password provided");
     }
 
-    while let Some(_) = set.join_next().await {
-    }
-
+    while let Some(_) = set.join_next().await {}
 }
 
 fn generate_rustls_cert() -> ServerConfig {
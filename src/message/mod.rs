@@ -1,12 +1,16 @@
 use crate::proto::MessageKind;
-use crate::voice::{ClientBound, VoicePacket};
+use crate::voice::{ClientBound, SharedVoicePacket, VoicePacket};
 use bytes::Bytes;
 use std::sync::Arc;
 
+mod queue;
+
+pub use queue::ClientQueue;
+
 #[derive(Debug, Clone)]
 pub enum ClientMessage {
     RouteVoicePacket(VoicePacket<ClientBound>),
-    SendVoicePacket(Arc<VoicePacket<ClientBound>>),
+    SendVoicePacket(Arc<SharedVoicePacket>),
     SendMessage { kind: MessageKind, payload: Arc<Bytes> },
     Disconnect,
 }
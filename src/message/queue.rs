@@ -0,0 +1,125 @@
+use crate::error::MumbleError;
+use crate::message::ClientMessage;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Outbound queue of a client, consumed by the client task.
+///
+/// Control messages (state updates, crypt setup, disconnect...) are never dropped and always
+/// consumed first. Voice packets are dropped oldest first once the voice queue is full, late audio
+/// isn't worth delivering.
+pub struct ClientQueue {
+    queues: parking_lot::Mutex<Queues>,
+    notify: Notify,
+    voice_capacity: usize,
+    closed: AtomicBool,
+    /// Voice packets dropped because the voice queue was full
+    pub voice_dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queues {
+    control: VecDeque<(Instant, ClientMessage)>,
+    voice: VecDeque<(Instant, ClientMessage)>,
+}
+
+impl ClientQueue {
+    pub fn new(voice_capacity: usize) -> Self {
+        Self {
+            queues: parking_lot::Mutex::new(Queues::default()),
+            notify: Notify::new(),
+            voice_capacity: voice_capacity.max(1),
+            closed: AtomicBool::new(false),
+            voice_dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues the message, only fails once the queue is closed
+    pub fn send(&self, message: ClientMessage) -> Result<(), MumbleError> {
+        if self.is_closed() {
+            return Err(MumbleError::QueueClosed);
+        }
+
+        {
+            let mut queues = self.queues.lock();
+            let now = Instant::now();
+
+            match message {
+                ClientMessage::RouteVoicePacket(_) | ClientMessage::SendVoicePacket(_) => {
+                    if queues.voice.len() >= self.voice_capacity {
+                        queues.voice.pop_front();
+
+                        self.voice_dropped.fetch_add(1, Ordering::Relaxed);
+                        crate::metrics::CLIENT_QUEUE_DROPPED_TOTAL.inc();
+                    }
+
+                    queues.voice.push_back((now, message));
+                }
+                _ => queues.control.push_back((now, message)),
+            }
+        }
+
+        // there is a single consumer, if it isn't waiting the permit is kept for its next wait
+        self.notify.notify_one();
+
+        Ok(())
+    }
+
+    /// Waits for the next message, control messages first, returns `None` once the queue is closed
+    pub async fn recv(&self) -> Option<ClientMessage> {
+        loop {
+            {
+                let mut queues = self.queues.lock();
+
+                if let Some((_, message)) = queues.control.pop_front().or_else(|| queues.voice.pop_front()) {
+                    return Some(message);
+                }
+            }
+
+            if self.is_closed() {
+                return None;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    /// Closes the queue, the pending messages are discarded and new ones refused
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        {
+            let mut queues = self.queues.lock();
+            queues.control.clear();
+            queues.voice.clear();
+        }
+
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the amount of queued control messages and voice packets
+    pub fn depth(&self) -> (usize, usize) {
+        let queues = self.queues.lock();
+
+        (queues.control.len(), queues.voice.len())
+    }
+
+    /// How long the oldest queued message has been waiting, a consumer which can't keep up with its
+    /// messages has a growing backlog
+    pub fn backlog(&self) -> Duration {
+        let queues = self.queues.lock();
+
+        [queues.control.front(), queues.voice.front()]
+            .into_iter()
+            .flatten()
+            .map(|(queued_at, _)| queued_at.elapsed())
+            .max()
+            .unwrap_or_default()
+    }
+}
@@ -1,6 +1,9 @@
 use lazy_static::lazy_static;
-use prometheus::{opts, register_int_counter_vec, register_int_gauge};
-use prometheus::{IntCounterVec, IntGauge};
+use prometheus::{
+    histogram_opts, opts, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec,
+};
+use prometheus::{HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
 
 lazy_static! {
     pub static ref MESSAGES_TOTAL: IntCounterVec = register_int_counter_vec!(
@@ -29,4 +32,96 @@ lazy_static! {
         &["protocol", "direction", "kind"]
     )
     .expect("can't create a metric");
+    pub static ref CLIENTS_BY_TRANSPORT: IntGaugeVec = register_int_gauge_vec!(
+        opts!("zumble_clients_by_transport", "clients by transport of their voice packets"),
+        &["transport"]
+    )
+    .expect("can't create a metric");
+    pub static ref UDP_FALLBACKS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_udp_fallbacks_total",
+        "clients falling back to tcp because they don't receive udp packets"
+    ))
+    .expect("can't create a metric");
+    pub static ref CRYPT_RESYNCS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("zumble_crypt_resyncs_total", "resynchronizations of the crypt state of the clients"),
+        &["cause"]
+    )
+    .expect("can't create a metric");
+    pub static ref PING_RTT: HistogramVec = register_histogram_vec!(
+        histogram_opts!(
+            "zumble_ping_rtt_milliseconds",
+            "round trip time of the pings of the clients",
+            vec![5.0, 10.0, 20.0, 35.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0, 500.0, 1000.0]
+        ),
+        &["transport"]
+    )
+    .expect("can't create a metric");
+    pub static ref PING_JITTER: HistogramVec = register_histogram_vec!(
+        histogram_opts!(
+            "zumble_ping_jitter_milliseconds",
+            "rolling jitter of the round trip time of the pings of the clients",
+            vec![1.0, 2.0, 5.0, 10.0, 20.0, 35.0, 50.0, 100.0, 200.0, 500.0]
+        ),
+        &["transport"]
+    )
+    .expect("can't create a metric");
+    pub static ref REJECTED_CLIENTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("zumble_rejected_clients_total", "clients rejected after authenticating"),
+        &["reason"]
+    )
+    .expect("can't create a metric");
+    pub static ref HANDSHAKES_PENDING: IntGauge = register_int_gauge!(opts!(
+        "zumble_handshakes_pending",
+        "connections which didn't complete their handshake yet"
+    ))
+    .expect("can't create a metric");
+    pub static ref HANDSHAKE_TIMEOUTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "zumble_handshake_timeouts_total",
+            "connections closed because a handshake stage timed out"
+        ),
+        &["stage"]
+    )
+    .expect("can't create a metric");
+    pub static ref OVERSIZED_FRAMES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "zumble_oversized_frames_total",
+            "frames rejected because they are larger than the limit of their kind"
+        ),
+        &["kind"]
+    )
+    .expect("can't create a metric");
+    pub static ref CLIENT_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        opts!("zumble_client_queue_depth", "messages waiting in the outbound queue of a client"),
+        &["session", "queue"]
+    )
+    .expect("can't create a metric");
+    pub static ref CLIENT_QUEUE_DROPPED: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "zumble_client_queue_dropped",
+            "voice packets dropped from the outbound queue of a client"
+        ),
+        &["session"]
+    )
+    .expect("can't create a metric");
+    pub static ref CLIENT_QUEUE_DROPPED_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_client_queue_dropped_total",
+        "voice packets dropped because the outbound queue of the client was full"
+    ))
+    .expect("can't create a metric");
+    pub static ref TCP_WRITE_TIMEOUTS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_tcp_write_timeouts_total",
+        "clients disconnected because a tcp write timed out"
+    ))
+    .expect("can't create a metric");
+    pub static ref TCP_WRITER_FULL_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_tcp_writer_full_total",
+        "clients disconnected because their tcp write queue was full"
+    ))
+    .expect("can't create a metric");
+    pub static ref SLOW_CONSUMER_DISCONNECTS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_slow_consumer_disconnects_total",
+        "clients disconnected because they couldn't keep up with their outbound queue"
+    ))
+    .expect("can't create a metric");
 }
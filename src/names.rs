@@ -0,0 +1,93 @@
+use scc::hash_map::Entry;
+use scc::HashMap;
+
+/// Session ids of the clients by name, for the http api which addresses the players by name.
+///
+/// Several clients can share a name, every session is kept so the name still resolves to the
+/// remaining ones when one of them disconnects. The last client connected with a name is used.
+#[derive(Default)]
+pub struct ClientNames {
+    sessions: HashMap<String, Vec<u32>>,
+}
+
+impl ClientNames {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            sessions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub async fn insert(&self, name: &str, session_id: u32) {
+        self.sessions
+            .entry_async(name.to_string())
+            .await
+            .or_default()
+            .get_mut()
+            .push(session_id);
+    }
+
+    pub async fn remove(&self, name: &str, session_id: u32) {
+        if let Entry::Occupied(mut entry) = self.sessions.entry_async(name.to_string()).await {
+            entry.get_mut().retain(|id| *id != session_id);
+
+            if entry.get().is_empty() {
+                let _ = entry.remove();
+            }
+        }
+    }
+
+    /// Returns the session of the last client connected with this name
+    pub async fn get(&self, name: &str) -> Option<u32> {
+        self.sessions
+            .read_async(name, |_, sessions| sessions.last().copied())
+            .await
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn last_connected_client_is_used() {
+        let names = ClientNames::default();
+
+        names.insert("bob", 1).await;
+        names.insert("bob", 2).await;
+        names.insert("alice", 3).await;
+
+        assert_eq!(names.get("bob").await, Some(2));
+        assert_eq!(names.get("alice").await, Some(3));
+        assert_eq!(names.get("carol").await, None);
+    }
+
+    #[tokio::test]
+    async fn shared_name_resolves_to_remaining_client() {
+        let names = ClientNames::default();
+
+        names.insert("bob", 1).await;
+        names.insert("bob", 2).await;
+
+        names.remove("bob", 2).await;
+        assert_eq!(names.get("bob").await, Some(1));
+
+        names.insert("bob", 3).await;
+        names.remove("bob", 1).await;
+        assert_eq!(names.get("bob").await, Some(3));
+
+        names.remove("bob", 3).await;
+        assert_eq!(names.get("bob").await, None);
+    }
+
+    #[tokio::test]
+    async fn removing_unknown_session_keeps_the_others() {
+        let names = ClientNames::default();
+
+        names.insert("bob", 1).await;
+        names.remove("bob", 2).await;
+        names.remove("alice", 1).await;
+
+        assert_eq!(names.get("bob").await, Some(1));
+    }
+}
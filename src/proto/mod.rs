@@ -1,11 +1,62 @@
+use crate::config::TcpConfig;
 use crate::error::MumbleError;
-use crate::handler::Handler;
+use crate::proto::mumble::Version;
 use bytes::{BufMut, Bytes, BytesMut};
 use protobuf::Message;
 use std::fmt;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+// the generated code doesn't follow the lints of newer compilers
+#[allow(warnings, clippy::all)]
 pub mod mumble;
+#[allow(warnings, clippy::all)]
+pub mod mumble_udp;
+
+/// First protocol version where clients and servers may exchange voice packets using the
+/// `MumbleUDP` protobuf format instead of the legacy one
+pub const PROTOBUF_UDP_VERSION: u64 = version_v2(1, 5, 0);
+
+/// Encodes a version using the legacy format (2-byte major, 1-byte minor, 1-byte patch)
+pub const fn version_v1(major: u32, minor: u32, patch: u32) -> u32 {
+    (major << 16) | (minor << 8) | patch
+}
+
+/// Encodes a version using the `version_v2` format (2-byte major, minor and patch, 2 reserved bytes)
+pub const fn version_v2(major: u64, minor: u64, patch: u64) -> u64 {
+    (major << 48) | (minor << 32) | (patch << 16)
+}
+
+/// Converts a version in the legacy format to the `version_v2` format
+pub const fn legacy_version_to_v2(version: u32) -> u64 {
+    version_v2((version >> 16) as u64, ((version >> 8) & 0xff) as u64, (version & 0xff) as u64)
+}
+
+/// Returns the version of a `Version` message in the `version_v2` format, falling back to the
+/// legacy field for clients which don't send it
+pub fn get_version_v2(version: &Version) -> u64 {
+    if version.has_version_v2() {
+        return version.get_version_v2();
+    }
+
+    legacy_version_to_v2(version.get_version())
+}
+
+/// Formats a version in the `version_v2` format as `major.minor.patch`
+pub fn format_version_v2(version: u64) -> String {
+    format!("{}.{}.{}", version >> 48, (version >> 32) & 0xffff, (version >> 16) & 0xffff)
+}
+
+/// Parses a `major.minor.patch` version into the `version_v2` format
+pub fn parse_version_v2(version: &str) -> Option<u64> {
+    let mut parts = version.split('.').map(|part| part.parse::<u16>().ok());
+
+    let version = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => version_v2(major as u64, minor as u64, patch as u64),
+        _ => return None,
+    };
+
+    Some(version)
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum MessageKind {
@@ -134,39 +185,30 @@ pub async fn send_message<T: Message, S: AsyncWrite + Unpin>(kind: MessageKind,
     Ok(())
 }
 
-pub async fn expected_message<T: Message + Handler, S: AsyncRead + Unpin>(
-    kind: MessageKind,
-    stream: &mut S,
-    retry: u8,
-) -> Result<T, MumbleError> {
-    let mut message_kind = stream.read_u16().await?;
-    let mut retry = retry;
-
-    while message_kind != kind as u16 && retry < 10 {
-        let size = stream.read_u32().await?;
-        let mut data = vec![0; size as usize];
-        stream.read_exact(&mut data).await?;
-
-        retry += 1;
-
-        if message_kind == MessageKind::UDPTunnel as u16 {
-            message_kind = stream.read_u16().await?;
-        } else {
-            return Err(MumbleError::UnexpectedMessageKind(message_kind));
-        }
-    }
+/// Reads the size and the payload of a frame of the given kind, frames larger than the limit of their
+/// kind are rejected before allocating the payload
+pub async fn read_payload<S: AsyncRead + Unpin>(kind: MessageKind, stream: &mut S, limits: &TcpConfig) -> Result<BytesMut, MumbleError> {
+    let size = stream.read_u32().await?;
+    let max_size = limits.get_max_frame_size(kind);
 
-    get_message(stream).await
-}
+    if size > max_size {
+        crate::metrics::OVERSIZED_FRAMES_TOTAL
+            .with_label_values(&[kind.to_string().as_str()])
+            .inc();
 
-pub async fn get_message<T: Message + Handler, S: AsyncRead + Unpin>(stream: &mut S) -> Result<T, MumbleError> {
-    let size = stream.read_u32().await?;
-    let mut data = vec![0; size as usize];
+        return Err(MumbleError::FrameTooLarge { kind, size, max_size });
+    }
+
+    let mut data = BytesMut::zeroed(size as usize);
     stream.read_exact(&mut data).await?;
 
-    let message = T::parse_from_bytes(data.as_slice())?;
+    Ok(data)
+}
 
-    tracing::trace!("received message: {:?}, {:?}", std::any::type_name::<T>(), message);
+/// Reads the next frame of the stream, returns its kind and its payload
+pub async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, limits: &TcpConfig) -> Result<(MessageKind, BytesMut), MumbleError> {
+    let kind = MessageKind::try_from(stream.read_u16().await?)?;
+    let payload = read_payload(kind, stream, limits).await?;
 
-    Ok(message)
+    Ok((kind, payload))
 }
@@ -0,0 +1,820 @@
+// This file is generated by rust-protobuf 2.27.1. Do not edit
+// @generated
+
+// https://github.com/rust-lang/rust-clippy/issues/702
+#![allow(unknown_lints)]
+#![allow(clippy::all)]
+
+#![allow(unused_attributes)]
+#![cfg_attr(rustfmt, rustfmt::skip)]
+
+#![allow(dead_code)]
+#![allow(missing_docs)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+#![allow(trivial_casts)]
+#![allow(unused_imports)]
+#![allow(unused_results)]
+//! Generated file from `src/proto/mumble_udp.proto`
+
+/// Generated files are compatible only with the same version
+/// of protobuf runtime.
+// const _PROTOBUF_VERSION_CHECK: () = ::protobuf::VERSION_2_27_1;
+
+#[derive(PartialEq,Clone,Default)]
+pub struct Audio {
+    // message fields
+    pub sender_session: u32,
+    pub frame_number: u64,
+    pub opus_data: ::std::vec::Vec<u8>,
+    pub positional_data: ::std::vec::Vec<f32>,
+    pub volume_adjustment: f32,
+    pub is_terminator: bool,
+    // message oneof groups
+    pub Header: ::std::option::Option<Audio_oneof_Header>,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a Audio {
+    fn default() -> &'a Audio {
+        <Audio as ::protobuf::Message>::default_instance()
+    }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub enum Audio_oneof_Header {
+    target(u32),
+    context(u32),
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        ::std::default::Default::default()
+    }
+
+    // uint32 target = 1;
+
+
+    pub fn get_target(&self) -> u32 {
+        match self.Header {
+            ::std::option::Option::Some(Audio_oneof_Header::target(v)) => v,
+            _ => 0,
+        }
+    }
+    pub fn clear_target(&mut self) {
+        self.Header = ::std::option::Option::None;
+    }
+
+    pub fn has_target(&self) -> bool {
+        match self.Header {
+            ::std::option::Option::Some(Audio_oneof_Header::target(..)) => true,
+            _ => false,
+        }
+    }
+
+    // Param is passed by value, moved
+    pub fn set_target(&mut self, v: u32) {
+        self.Header = ::std::option::Option::Some(Audio_oneof_Header::target(v))
+    }
+
+    // uint32 context = 2;
+
+
+    pub fn get_context(&self) -> u32 {
+        match self.Header {
+            ::std::option::Option::Some(Audio_oneof_Header::context(v)) => v,
+            _ => 0,
+        }
+    }
+    pub fn clear_context(&mut self) {
+        self.Header = ::std::option::Option::None;
+    }
+
+    pub fn has_context(&self) -> bool {
+        match self.Header {
+            ::std::option::Option::Some(Audio_oneof_Header::context(..)) => true,
+            _ => false,
+        }
+    }
+
+    // Param is passed by value, moved
+    pub fn set_context(&mut self, v: u32) {
+        self.Header = ::std::option::Option::Some(Audio_oneof_Header::context(v))
+    }
+
+    // uint32 sender_session = 3;
+
+
+    pub fn get_sender_session(&self) -> u32 {
+        self.sender_session
+    }
+    pub fn clear_sender_session(&mut self) {
+        self.sender_session = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_sender_session(&mut self, v: u32) {
+        self.sender_session = v;
+    }
+
+    // uint64 frame_number = 4;
+
+
+    pub fn get_frame_number(&self) -> u64 {
+        self.frame_number
+    }
+    pub fn clear_frame_number(&mut self) {
+        self.frame_number = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_frame_number(&mut self, v: u64) {
+        self.frame_number = v;
+    }
+
+    // bytes opus_data = 5;
+
+
+    pub fn get_opus_data(&self) -> &[u8] {
+        &self.opus_data
+    }
+    pub fn clear_opus_data(&mut self) {
+        self.opus_data.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_opus_data(&mut self, v: ::std::vec::Vec<u8>) {
+        self.opus_data = v;
+    }
+
+    // Mutable pointer to the field.
+    // If field is not initialized, it is initialized with default value first.
+    pub fn mut_opus_data(&mut self) -> &mut ::std::vec::Vec<u8> {
+        &mut self.opus_data
+    }
+
+    // Take field
+    pub fn take_opus_data(&mut self) -> ::std::vec::Vec<u8> {
+        ::std::mem::replace(&mut self.opus_data, ::std::vec::Vec::new())
+    }
+
+    // repeated float positional_data = 6;
+
+
+    pub fn get_positional_data(&self) -> &[f32] {
+        &self.positional_data
+    }
+    pub fn clear_positional_data(&mut self) {
+        self.positional_data.clear();
+    }
+
+    // Param is passed by value, moved
+    pub fn set_positional_data(&mut self, v: ::std::vec::Vec<f32>) {
+        self.positional_data = v;
+    }
+
+    // Mutable pointer to the field.
+    pub fn mut_positional_data(&mut self) -> &mut ::std::vec::Vec<f32> {
+        &mut self.positional_data
+    }
+
+    // Take field
+    pub fn take_positional_data(&mut self) -> ::std::vec::Vec<f32> {
+        ::std::mem::replace(&mut self.positional_data, ::std::vec::Vec::new())
+    }
+
+    // float volume_adjustment = 7;
+
+
+    pub fn get_volume_adjustment(&self) -> f32 {
+        self.volume_adjustment
+    }
+    pub fn clear_volume_adjustment(&mut self) {
+        self.volume_adjustment = 0.;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_volume_adjustment(&mut self, v: f32) {
+        self.volume_adjustment = v;
+    }
+
+    // bool is_terminator = 16;
+
+
+    pub fn get_is_terminator(&self) -> bool {
+        self.is_terminator
+    }
+    pub fn clear_is_terminator(&mut self) {
+        self.is_terminator = false;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_is_terminator(&mut self, v: bool) {
+        self.is_terminator = v;
+    }
+}
+
+impl ::protobuf::Message for Audio {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    self.Header = ::std::option::Option::Some(Audio_oneof_Header::target(is.read_uint32()?));
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    self.Header = ::std::option::Option::Some(Audio_oneof_Header::context(is.read_uint32()?));
+                },
+                3 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.sender_session = tmp;
+                },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.frame_number = tmp;
+                },
+                5 => {
+                    ::protobuf::rt::read_singular_proto3_bytes_into(wire_type, is, &mut self.opus_data)?;
+                },
+                6 => {
+                    ::protobuf::rt::read_repeated_float_into(wire_type, is, &mut self.positional_data)?;
+                },
+                7 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeFixed32 {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_float()?;
+                    self.volume_adjustment = tmp;
+                },
+                16 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_bool()?;
+                    self.is_terminator = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.sender_session != 0 {
+            my_size += ::protobuf::rt::value_size(3, self.sender_session, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.frame_number != 0 {
+            my_size += ::protobuf::rt::value_size(4, self.frame_number, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if !self.opus_data.is_empty() {
+            my_size += ::protobuf::rt::bytes_size(5, &self.opus_data);
+        }
+        my_size += 5 * self.positional_data.len() as u32;
+        if self.volume_adjustment != 0. {
+            my_size += 5;
+        }
+        if self.is_terminator != false {
+            my_size += 3;
+        }
+        if let ::std::option::Option::Some(ref v) = self.Header {
+            match v {
+                &Audio_oneof_Header::target(v) => {
+                    my_size += ::protobuf::rt::value_size(1, v, ::protobuf::wire_format::WireTypeVarint);
+                },
+                &Audio_oneof_Header::context(v) => {
+                    my_size += ::protobuf::rt::value_size(2, v, ::protobuf::wire_format::WireTypeVarint);
+                },
+            };
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.sender_session != 0 {
+            os.write_uint32(3, self.sender_session)?;
+        }
+        if self.frame_number != 0 {
+            os.write_uint64(4, self.frame_number)?;
+        }
+        if !self.opus_data.is_empty() {
+            os.write_bytes(5, &self.opus_data)?;
+        }
+        for v in &self.positional_data {
+            os.write_float(6, *v)?;
+        };
+        if self.volume_adjustment != 0. {
+            os.write_float(7, self.volume_adjustment)?;
+        }
+        if self.is_terminator != false {
+            os.write_bool(16, self.is_terminator)?;
+        }
+        if let ::std::option::Option::Some(ref v) = self.Header {
+            match v {
+                &Audio_oneof_Header::target(v) => {
+                    os.write_uint32(1, v)?;
+                },
+                &Audio_oneof_Header::context(v) => {
+                    os.write_uint32(2, v)?;
+                },
+            };
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> Audio {
+        Audio::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_singular_u32_accessor::<_>(
+                "target",
+                Audio::has_target,
+                Audio::get_target,
+            ));
+            fields.push(::protobuf::reflect::accessor::make_singular_u32_accessor::<_>(
+                "context",
+                Audio::has_context,
+                Audio::get_context,
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "sender_session",
+                |m: &Audio| { &m.sender_session },
+                |m: &mut Audio| { &mut m.sender_session },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "frame_number",
+                |m: &Audio| { &m.frame_number },
+                |m: &mut Audio| { &mut m.frame_number },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBytes>(
+                "opus_data",
+                |m: &Audio| { &m.opus_data },
+                |m: &mut Audio| { &mut m.opus_data },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_vec_accessor::<_, ::protobuf::types::ProtobufTypeFloat>(
+                "positional_data",
+                |m: &Audio| { &m.positional_data },
+                |m: &mut Audio| { &mut m.positional_data },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeFloat>(
+                "volume_adjustment",
+                |m: &Audio| { &m.volume_adjustment },
+                |m: &mut Audio| { &mut m.volume_adjustment },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBool>(
+                "is_terminator",
+                |m: &Audio| { &m.is_terminator },
+                |m: &mut Audio| { &mut m.is_terminator },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Audio>(
+                "Audio",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static Audio {
+        static instance: ::protobuf::rt::LazyV2<Audio> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Audio::new)
+    }
+}
+
+impl ::protobuf::Clear for Audio {
+    fn clear(&mut self) {
+        self.Header = ::std::option::Option::None;
+        self.Header = ::std::option::Option::None;
+        self.sender_session = 0;
+        self.frame_number = 0;
+        self.opus_data.clear();
+        self.positional_data.clear();
+        self.volume_adjustment = 0.;
+        self.is_terminator = false;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for Audio {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for Audio {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+#[derive(PartialEq,Clone,Default)]
+pub struct Ping {
+    // message fields
+    pub timestamp: u64,
+    pub request_extended_information: bool,
+    pub server_version_v2: u64,
+    pub user_count: u32,
+    pub max_user_count: u32,
+    pub max_bandwidth_per_user: u32,
+    // special fields
+    pub unknown_fields: ::protobuf::UnknownFields,
+    pub cached_size: ::protobuf::CachedSize,
+}
+
+impl<'a> ::std::default::Default for &'a Ping {
+    fn default() -> &'a Ping {
+        <Ping as ::protobuf::Message>::default_instance()
+    }
+}
+
+impl Ping {
+    pub fn new() -> Ping {
+        ::std::default::Default::default()
+    }
+
+    // uint64 timestamp = 1;
+
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+    pub fn clear_timestamp(&mut self) {
+        self.timestamp = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_timestamp(&mut self, v: u64) {
+        self.timestamp = v;
+    }
+
+    // bool request_extended_information = 2;
+
+
+    pub fn get_request_extended_information(&self) -> bool {
+        self.request_extended_information
+    }
+    pub fn clear_request_extended_information(&mut self) {
+        self.request_extended_information = false;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_request_extended_information(&mut self, v: bool) {
+        self.request_extended_information = v;
+    }
+
+    // uint64 server_version_v2 = 3;
+
+
+    pub fn get_server_version_v2(&self) -> u64 {
+        self.server_version_v2
+    }
+    pub fn clear_server_version_v2(&mut self) {
+        self.server_version_v2 = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_server_version_v2(&mut self, v: u64) {
+        self.server_version_v2 = v;
+    }
+
+    // uint32 user_count = 4;
+
+
+    pub fn get_user_count(&self) -> u32 {
+        self.user_count
+    }
+    pub fn clear_user_count(&mut self) {
+        self.user_count = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_user_count(&mut self, v: u32) {
+        self.user_count = v;
+    }
+
+    // uint32 max_user_count = 5;
+
+
+    pub fn get_max_user_count(&self) -> u32 {
+        self.max_user_count
+    }
+    pub fn clear_max_user_count(&mut self) {
+        self.max_user_count = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_max_user_count(&mut self, v: u32) {
+        self.max_user_count = v;
+    }
+
+    // uint32 max_bandwidth_per_user = 6;
+
+
+    pub fn get_max_bandwidth_per_user(&self) -> u32 {
+        self.max_bandwidth_per_user
+    }
+    pub fn clear_max_bandwidth_per_user(&mut self) {
+        self.max_bandwidth_per_user = 0;
+    }
+
+    // Param is passed by value, moved
+    pub fn set_max_bandwidth_per_user(&mut self, v: u32) {
+        self.max_bandwidth_per_user = v;
+    }
+}
+
+impl ::protobuf::Message for Ping {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn merge_from(&mut self, is: &mut ::protobuf::CodedInputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.timestamp = tmp;
+                },
+                2 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_bool()?;
+                    self.request_extended_information = tmp;
+                },
+                3 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint64()?;
+                    self.server_version_v2 = tmp;
+                },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.user_count = tmp;
+                },
+                5 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.max_user_count = tmp;
+                },
+                6 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    let tmp = is.read_uint32()?;
+                    self.max_bandwidth_per_user = tmp;
+                },
+                _ => {
+                    ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
+                },
+            };
+        }
+        ::std::result::Result::Ok(())
+    }
+
+    // Compute sizes of nested messages
+    #[allow(unused_variables)]
+    fn compute_size(&self) -> u32 {
+        let mut my_size = 0;
+        if self.timestamp != 0 {
+            my_size += ::protobuf::rt::value_size(1, self.timestamp, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.request_extended_information != false {
+            my_size += 2;
+        }
+        if self.server_version_v2 != 0 {
+            my_size += ::protobuf::rt::value_size(3, self.server_version_v2, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.user_count != 0 {
+            my_size += ::protobuf::rt::value_size(4, self.user_count, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.max_user_count != 0 {
+            my_size += ::protobuf::rt::value_size(5, self.max_user_count, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.max_bandwidth_per_user != 0 {
+            my_size += ::protobuf::rt::value_size(6, self.max_bandwidth_per_user, ::protobuf::wire_format::WireTypeVarint);
+        }
+        my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
+        self.cached_size.set(my_size);
+        my_size
+    }
+
+    fn write_to_with_cached_sizes(&self, os: &mut ::protobuf::CodedOutputStream<'_>) -> ::protobuf::ProtobufResult<()> {
+        if self.timestamp != 0 {
+            os.write_uint64(1, self.timestamp)?;
+        }
+        if self.request_extended_information != false {
+            os.write_bool(2, self.request_extended_information)?;
+        }
+        if self.server_version_v2 != 0 {
+            os.write_uint64(3, self.server_version_v2)?;
+        }
+        if self.user_count != 0 {
+            os.write_uint32(4, self.user_count)?;
+        }
+        if self.max_user_count != 0 {
+            os.write_uint32(5, self.max_user_count)?;
+        }
+        if self.max_bandwidth_per_user != 0 {
+            os.write_uint32(6, self.max_bandwidth_per_user)?;
+        }
+        os.write_unknown_fields(self.get_unknown_fields())?;
+        ::std::result::Result::Ok(())
+    }
+
+    fn get_cached_size(&self) -> u32 {
+        self.cached_size.get()
+    }
+
+    fn get_unknown_fields(&self) -> &::protobuf::UnknownFields {
+        &self.unknown_fields
+    }
+
+    fn mut_unknown_fields(&mut self) -> &mut ::protobuf::UnknownFields {
+        &mut self.unknown_fields
+    }
+
+    fn as_any(&self) -> &dyn (::std::any::Any) {
+        self as &dyn (::std::any::Any)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn (::std::any::Any) {
+        self as &mut dyn (::std::any::Any)
+    }
+    fn into_any(self: ::std::boxed::Box<Self>) -> ::std::boxed::Box<dyn (::std::any::Any)> {
+        self
+    }
+
+    fn descriptor(&self) -> &'static ::protobuf::reflect::MessageDescriptor {
+        Self::descriptor_static()
+    }
+
+    fn new() -> Ping {
+        Ping::new()
+    }
+
+    fn descriptor_static() -> &'static ::protobuf::reflect::MessageDescriptor {
+        static descriptor: ::protobuf::rt::LazyV2<::protobuf::reflect::MessageDescriptor> = ::protobuf::rt::LazyV2::INIT;
+        descriptor.get(|| {
+            let mut fields = ::std::vec::Vec::new();
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "timestamp",
+                |m: &Ping| { &m.timestamp },
+                |m: &mut Ping| { &mut m.timestamp },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeBool>(
+                "request_extended_information",
+                |m: &Ping| { &m.request_extended_information },
+                |m: &mut Ping| { &mut m.request_extended_information },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint64>(
+                "server_version_v2",
+                |m: &Ping| { &m.server_version_v2 },
+                |m: &mut Ping| { &mut m.server_version_v2 },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "user_count",
+                |m: &Ping| { &m.user_count },
+                |m: &mut Ping| { &mut m.user_count },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "max_user_count",
+                |m: &Ping| { &m.max_user_count },
+                |m: &mut Ping| { &mut m.max_user_count },
+            ));
+            fields.push(::protobuf::reflect::accessor::make_simple_field_accessor::<_, ::protobuf::types::ProtobufTypeUint32>(
+                "max_bandwidth_per_user",
+                |m: &Ping| { &m.max_bandwidth_per_user },
+                |m: &mut Ping| { &mut m.max_bandwidth_per_user },
+            ));
+            ::protobuf::reflect::MessageDescriptor::new_pb_name::<Ping>(
+                "Ping",
+                fields,
+                file_descriptor_proto()
+            )
+        })
+    }
+
+    fn default_instance() -> &'static Ping {
+        static instance: ::protobuf::rt::LazyV2<Ping> = ::protobuf::rt::LazyV2::INIT;
+        instance.get(Ping::new)
+    }
+}
+
+impl ::protobuf::Clear for Ping {
+    fn clear(&mut self) {
+        self.timestamp = 0;
+        self.request_extended_information = false;
+        self.server_version_v2 = 0;
+        self.user_count = 0;
+        self.max_user_count = 0;
+        self.max_bandwidth_per_user = 0;
+        self.unknown_fields.clear();
+    }
+}
+
+impl ::std::fmt::Debug for Ping {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        ::protobuf::text_format::fmt(self, f)
+    }
+}
+
+impl ::protobuf::reflect::ProtobufValue for Ping {
+    fn as_ref(&self) -> ::protobuf::reflect::ReflectValueRef {
+        ::protobuf::reflect::ReflectValueRef::Message(self)
+    }
+}
+
+static file_descriptor_proto_data: &'static [u8] = b"\
+    \n\x1asrc/proto/mumble_udp.proto\x12\tMumbleUDP\"\xbb\x02\n\x05Audio\x12\
+    \x1a\n\x06target\x18\x01\x20\x01(\rH\0R\x06targetB\0\x12\x1c\n\x07contex\
+    t\x18\x02\x20\x01(\rH\0R\x07contextB\0\x12'\n\x0esender_session\x18\x03\
+    \x20\x01(\rR\rsenderSessionB\0\x12#\n\x0cframe_number\x18\x04\x20\x01(\
+    \x04R\x0bframeNumberB\0\x12\x1d\n\topus_data\x18\x05\x20\x01(\x0cR\x08op\
+    usDataB\0\x12)\n\x0fpositional_data\x18\x06\x20\x03(\x02R\x0epositionalD\
+    ataB\0\x12-\n\x11volume_adjustment\x18\x07\x20\x01(\x02R\x10volumeAdjust\
+    mentB\0\x12%\n\ris_terminator\x18\x10\x20\x01(\x08R\x0cisTerminatorB\0B\
+    \x08\n\x06Header:\0\"\x9a\x02\n\x04Ping\x12\x1e\n\ttimestamp\x18\x01\x20\
+    \x01(\x04R\ttimestampB\0\x12B\n\x1crequest_extended_information\x18\x02\
+    \x20\x01(\x08R\x1arequestExtendedInformationB\0\x12,\n\x11server_version\
+    _v2\x18\x03\x20\x01(\x04R\x0fserverVersionV2B\0\x12\x1f\n\nuser_count\
+    \x18\x04\x20\x01(\rR\tuserCountB\0\x12&\n\x0emax_user_count\x18\x05\x20\
+    \x01(\rR\x0cmaxUserCountB\0\x125\n\x16max_bandwidth_per_user\x18\x06\x20\
+    \x01(\rR\x13maxBandwidthPerUserB\0:\0B\0b\x06proto3\
+";
+
+static file_descriptor_proto_lazy: ::protobuf::rt::LazyV2<::protobuf::descriptor::FileDescriptorProto> = ::protobuf::rt::LazyV2::INIT;
+
+fn parse_descriptor_proto() -> ::protobuf::descriptor::FileDescriptorProto {
+    ::protobuf::Message::parse_from_bytes(file_descriptor_proto_data).unwrap()
+}
+
+pub fn file_descriptor_proto() -> &'static ::protobuf::descriptor::FileDescriptorProto {
+    file_descriptor_proto_lazy.get(|| {
+        parse_descriptor_proto()
+    })
+}
@@ -0,0 +1,60 @@
+use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::client::ClientRef;
+
+/// Precomputed list of the clients receiving some audio (the clients of a channel, the targets of a
+/// voice target...), it's rebuilt each time one of its clients changes so routing a voice packet is
+/// only a load of the list.
+///
+/// Refreshes can run concurrently, each one takes a generation with [`Recipients::start_refresh`]
+/// before reading the clients and its list is only stored if no later refresh stored one, so a
+/// stale list never replaces a newer one.
+#[derive(Default)]
+pub struct Recipients {
+    refreshes: AtomicU64,
+    current: ArcSwap<Generation>,
+}
+
+#[derive(Default)]
+struct Generation {
+    generation: u64,
+    clients: Arc<Vec<ClientRef>>,
+}
+
+impl Recipients {
+    pub fn get(&self) -> Arc<Vec<ClientRef>> {
+        Arc::clone(&self.current.load().clients)
+    }
+
+    /// Returns the generation of a new refresh, must be called before reading the clients
+    pub fn start_refresh(&self) -> u64 {
+        self.refreshes.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Stores the list built by the refresh of the given generation, unless a later refresh already
+    /// stored its list
+    pub fn set(&self, generation: u64, clients: Vec<ClientRef>) {
+        let new = Arc::new(Generation {
+            generation,
+            clients: Arc::new(clients),
+        });
+
+        let mut current = self.current.load();
+
+        loop {
+            if current.generation > generation {
+                return;
+            }
+
+            let previous = self.current.compare_and_swap(&*current, Arc::clone(&new));
+
+            if Arc::ptr_eq(&*previous, &*current) {
+                return;
+            }
+
+            current = previous;
+        }
+    }
+}
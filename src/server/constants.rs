@@ -1,4 +1,4 @@
-/// TODO: Add these to a server.toml file so its easier to configure
+/// Defaults for the values which can be changed in the server.toml file (see [crate::config::Config])
 /// The amount of players the server can support
 pub const MAX_CLIENTS: usize = 4096;
 
@@ -0,0 +1,81 @@
+use crate::config::TcpConfig;
+use crate::error::MumbleError;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Stages of a new connection, each one has to complete within its own timeout so idle or
+/// slowloris peers can't hold a task forever:
+///
+/// - `Tls`: the TLS handshake
+/// - `Version`: waiting for the version of the client, it may also authenticate directly
+/// - `Authenticate`: waiting for the authenticate message of the client
+/// - `Synced`: sending the codec version, channels, users and server sync to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    Tls,
+    Version,
+    Authenticate,
+    Synced,
+}
+
+impl fmt::Display for HandshakeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeStage::Tls => write!(f, "tls"),
+            HandshakeStage::Version => write!(f, "version"),
+            HandshakeStage::Authenticate => write!(f, "authenticate"),
+            HandshakeStage::Synced => write!(f, "synced"),
+        }
+    }
+}
+
+impl HandshakeStage {
+    pub fn timeout(self, config: &TcpConfig) -> Duration {
+        let timeout_ms = match self {
+            HandshakeStage::Tls => config.tls_timeout_ms,
+            HandshakeStage::Version => config.version_timeout_ms,
+            HandshakeStage::Authenticate => config.authenticate_timeout_ms,
+            HandshakeStage::Synced => config.sync_timeout_ms,
+        };
+
+        Duration::from_millis(timeout_ms)
+    }
+
+    /// Returns when the stage times out if it starts now
+    pub fn deadline(self, config: &TcpConfig) -> Instant {
+        Instant::now() + self.timeout(config)
+    }
+
+    /// Runs the future until the deadline of the stage, a timeout is counted in the metrics
+    pub async fn run_until<F: Future>(self, deadline: Instant, future: F) -> Result<F::Output, MumbleError> {
+        match tokio::time::timeout_at(deadline, future).await {
+            Ok(output) => Ok(output),
+            Err(_) => {
+                crate::metrics::HANDSHAKE_TIMEOUTS_TOTAL
+                    .with_label_values(&[self.to_string().as_str()])
+                    .inc();
+
+                Err(MumbleError::HandshakeTimeout(self))
+            }
+        }
+    }
+}
+
+/// Counts a connection in the pending handshakes metric until dropped
+pub struct PendingHandshake;
+
+impl PendingHandshake {
+    pub fn start() -> Self {
+        crate::metrics::HANDSHAKES_PENDING.inc();
+
+        Self
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        crate::metrics::HANDSHAKES_PENDING.dec();
+    }
+}
@@ -1,6 +1,13 @@
 pub mod constants;
+mod handshake;
 mod tcp;
+mod tcp_writer;
 mod udp;
+mod udp_reader;
+mod udp_writer;
 
+pub use handshake::{HandshakeStage, PendingHandshake};
 pub use tcp::create_tcp_server;
-pub use udp::create_udp_server;
+pub use tcp_writer::TcpWriter;
+pub use udp::{bind_udp_sockets, create_udp_server};
+pub use udp_writer::UdpWriter;
@@ -1,20 +1,17 @@
 use std::net::IpAddr;
-use std::time::Duration;
 
 use crate::client::{Client, ClientRef};
 use crate::handler::MessageHandler;
-use crate::message::ClientMessage;
-use crate::proto::mumble::Version;
-use crate::proto::MessageKind;
-use crate::server::constants::{MAX_BANDWIDTH_IN_BYTES, MAX_CLIENTS};
+use crate::proto::mumble::{Reject, Reject_RejectType, Version};
+use crate::proto::{format_version_v2, get_version_v2, send_message, MessageKind};
+use crate::server::{HandshakeStage, PendingHandshake};
 use crate::state::ServerStateRef;
+use crate::voice::VoiceProtocol;
 use anyhow::Context;
 use futures::TryFutureExt;
 use tokio::io::{self};
 use tokio::io::{AsyncWriteExt, ReadHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 pub async fn create_tcp_server(
@@ -39,10 +36,11 @@ pub async fn create_tcp_server(
         let state = state.clone();
 
         let cur_clients = state.clients.len();
+        let max_clients = state.config.max_clients;
         let addr = tcp_stream.peer_addr()?;
 
         // if we're over our max client count then we should shut down the tcp stream
-        if cur_clients >= MAX_CLIENTS {
+        if cur_clients >= max_clients {
             tokio::spawn(async move {
                 tcp_stream.shutdown().await.unwrap();
             });
@@ -50,11 +48,13 @@ pub async fn create_tcp_server(
                 "{:?} tried to join but the server is at maximum capacity ({}/{})",
                 addr,
                 cur_clients,
-                MAX_CLIENTS
+                max_clients
             );
             continue;
         }
 
+        let tls_deadline = HandshakeStage::Tls.deadline(&state.config.tcp);
+
         let handle_accept_tls_stream = async move {
             let peer_ip = addr.ip();
 
@@ -64,16 +64,7 @@ pub async fn create_tcp_server(
                 .accept(tcp_stream)
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Client TLS connect fail: {:?}", e)));
 
-            const TLS_TIMEOUT: u64 = 5;
-
-            let stream = tokio::time::timeout(Duration::from_secs(TLS_TIMEOUT), stream).map_err(move |_e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    format!("Client TLS handshake timedout after {} seconds", TLS_TIMEOUT),
-                )
-            });
-
-            let res: Result<TlsStream<TcpStream>, anyhow::Error> = match stream.await {
+            let res: Result<TlsStream<TcpStream>, anyhow::Error> = match HandshakeStage::Tls.run_until(tls_deadline, stream).await {
                 Ok(Ok(tls_stream)) => Ok(tls_stream),
                 Err(e) => Err(e.into()),
                 Ok(Err(e)) => Err(e.into()),
@@ -83,12 +74,18 @@ pub async fn create_tcp_server(
         };
 
         tokio::spawn(async move {
+            let pending_handshake = PendingHandshake::start();
+
             let (tls_stream, peer_ip) = match handle_accept_tls_stream.await {
                 (Ok(tls_stream), peer_ip) => (tls_stream, peer_ip),
-                (Err(e), _) => return Err(e),
+                (Err(e), _) => {
+                    tracing::debug!("{:?} failed to connect: {}", addr, e);
+
+                    return Err(e);
+                }
             };
 
-            handle_new_client(tls_stream, peer_ip, server_version, state).await
+            handle_new_client(tls_stream, peer_ip, server_version, state, pending_handshake).await
         });
     }
 }
@@ -98,24 +95,66 @@ async fn handle_new_client(
     peer_ip: IpAddr,
     server_version: Version,
     state: ServerStateRef,
+    pending_handshake: PendingHandshake,
 ) -> Result<(), anyhow::Error> {
-    let (version, authenticate, crypt_state) = Client::init(&mut tls_stream, server_version).await.context("init client")?;
+    let (version, authenticate, crypt_state) = Client::init(&mut tls_stream, server_version.clone(), &state.config.tcp)
+        .await
+        .map_err(|e| {
+            tracing::debug!("{} failed to authenticate: {}", peer_ip, e);
+
+            e
+        })
+        .context("init client")?;
+
+    if let Some(min_version) = state.config.get_min_client_version() {
+        if get_version_v2(&version) < min_version {
+            tracing::info!(
+                "rejecting {} from {}: version {} is older than {}",
+                authenticate.get_username(),
+                peer_ip,
+                format_version_v2(get_version_v2(&version)),
+                format_version_v2(min_version)
+            );
+
+            let mut reject = Reject::new();
+            reject.set_field_type(Reject_RejectType::WrongVersion);
+            reject.set_reason(format!("This server requires Mumble {} or newer", format_version_v2(min_version)));
+
+            crate::metrics::REJECTED_CLIENTS_TOTAL.with_label_values(&["wrong_version"]).inc();
+
+            send_message(MessageKind::Reject, &reject, &mut tls_stream).await?;
+            let _ = tls_stream.shutdown().await;
+
+            return Ok(());
+        }
+    }
+
+    let voice_protocol = VoiceProtocol::negotiate(&server_version, &version);
 
     let (read, write) = io::split(tls_stream);
-    let (tx, rx) = mpsc::channel(MAX_BANDWIDTH_IN_BYTES);
 
     let username = authenticate.get_username().to_string();
-    let client = state.add_client(version, authenticate, crypt_state, write, tx, peer_ip).await;
+    let client = state
+        .add_client(version, voice_protocol, authenticate, crypt_state, write, peer_ip)
+        .await;
 
-    tracing::info!("TCP new client {} connected {}", username, peer_ip);
+    tracing::info!(
+        "TCP new client {} connected {} using {:?} voice protocol",
+        username,
+        peer_ip,
+        voice_protocol
+    );
 
     let state_cl = state.clone();
     let client_cl = client.clone();
-    match client_run(read, rx, &state_cl, &client_cl).await {
+    match client_run(read, &state_cl, &client_cl, pending_handshake).await {
         Ok(_) => (),
         Err(_e) => (),
     }
 
+    // nothing consumes the queue anymore, stop accepting messages for this client
+    client.publisher.close();
+
     tracing::info!("client {} disconnected", username);
 
     state_cl.disconnect(client.session_id).await;
@@ -125,15 +164,15 @@ async fn handle_new_client(
 
 pub async fn client_run(
     mut read: ReadHalf<TlsStream<TcpStream>>,
-    mut receiver: Receiver<ClientMessage>,
     state: &ServerStateRef,
     client: &ClientRef,
+    pending_handshake: PendingHandshake,
 ) -> Result<(), anyhow::Error> {
-    let codec_version = { state.codec_state.get_codec_version() };
+    let sync = async {
+        let codec_version = { state.codec_state.get_codec_version() };
 
-    client.send_message(MessageKind::CodecVersion, &codec_version).await?;
+        client.send_message(MessageKind::CodecVersion, &codec_version).await?;
 
-    {
         client.sync_client_and_channels(state).await.map_err(|e| {
             tracing::error!("init client error during channel sync: {:?}", e);
 
@@ -141,9 +180,19 @@ pub async fn client_run(
         })?;
 
         client.send_my_user_state().await?;
-        client.send_server_sync().await?;
-        client.send_server_config().await?;
-    }
+        client.send_server_sync(state.config.max_bandwidth).await?;
+        client.send_server_config(&state.config).await
+    };
+
+    let sync_deadline = HandshakeStage::Synced.deadline(&state.config.tcp);
+
+    HandshakeStage::Synced.run_until(sync_deadline, sync).await.map_err(|e| {
+        tracing::warn!("{} {}", client, e);
+
+        e
+    })??;
+
+    drop(pending_handshake);
 
     let user_state = { client.get_user_state() };
 
@@ -155,7 +204,7 @@ pub async fn client_run(
     }
 
     loop {
-        match MessageHandler::handle(&mut read, &mut receiver, state, client).await {
+        match MessageHandler::handle(&mut read, state, client).await {
             Ok(_) => (),
             Err(e) => {
                 if e.is::<io::Error>() {
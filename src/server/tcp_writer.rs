@@ -0,0 +1,123 @@
+use crate::error::MumbleError;
+use bytes::{Bytes, BytesMut};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio_rustls::server::TlsStream;
+use tokio_util::sync::CancellationToken;
+
+/// Maximum amount of frames written together
+const BATCH_SIZE: usize = 64;
+
+/// Amount of frames waiting to be written on top of the initial sync, a client whose writer can't
+/// keep up is disconnected once the queue is full
+const QUEUE_SIZE: usize = 1024;
+
+/// Handle to the task writing the frames of a client on its tcp stream.
+///
+/// The frames queued while the task is writing are written together, so they share as few TLS
+/// records as possible. A peer which doesn't accept a write within the timeout, or whose queue is
+/// full, is considered stuck, the writer stops and the client gets disconnected.
+pub struct TcpWriter {
+    sender: Sender<Bytes>,
+    closed: CancellationToken,
+}
+
+impl TcpWriter {
+    /// `sync_size` is the amount of frames sent at once by the initial sync of the client
+    pub fn spawn(write: WriteHalf<TlsStream<TcpStream>>, write_timeout: Duration, sync_size: usize, log_name: String) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE + sync_size);
+        let closed = CancellationToken::new();
+
+        tokio::spawn(write_loop(write, receiver, write_timeout, closed.clone(), log_name));
+
+        Self { sender, closed }
+    }
+
+    /// Queues the frame without waiting, the writer is closed if the queue is full so a stuck peer
+    /// never blocks the sender
+    pub fn send(&self, frame: Bytes) -> Result<(), MumbleError> {
+        match self.sender.try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                crate::metrics::TCP_WRITER_FULL_TOTAL.inc();
+
+                self.close();
+
+                Err(MumbleError::WriterFull)
+            }
+            Err(TrySendError::Closed(_)) => Err(MumbleError::WriterClosed),
+        }
+    }
+
+    /// Stops the writer and shuts down the write side of the stream
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Completes once the writer stopped, after a failed write or a close
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+}
+
+async fn write_loop(
+    mut write: WriteHalf<TlsStream<TcpStream>>,
+    mut receiver: Receiver<Bytes>,
+    write_timeout: Duration,
+    closed: CancellationToken,
+    log_name: String,
+) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut buffer = BytesMut::new();
+
+    loop {
+        let count = tokio::select! {
+            count = receiver.recv_many(&mut batch, BATCH_SIZE) => count,
+            _ = closed.cancelled() => break,
+        };
+
+        if count == 0 {
+            break;
+        }
+
+        for frame in batch.drain(..) {
+            buffer.extend_from_slice(&frame);
+        }
+
+        let result = tokio::select! {
+            result = tokio::time::timeout(write_timeout, write_frames(&mut write, &buffer)) => result,
+            _ = closed.cancelled() => break,
+        };
+
+        buffer.clear();
+
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                tracing::error!("cannot write to {}: {}", log_name, e);
+
+                break;
+            }
+            Err(_) => {
+                tracing::warn!("write to {} timed out after {:?}, disconnecting", log_name, write_timeout);
+
+                crate::metrics::TCP_WRITE_TIMEOUTS_TOTAL.inc();
+
+                break;
+            }
+        }
+    }
+
+    closed.cancel();
+
+    // a stuck peer may not accept the close notify either
+    let _ = tokio::time::timeout(write_timeout, write.shutdown()).await;
+}
+
+async fn write_frames(write: &mut WriteHalf<TlsStream<TcpStream>>, buffer: &[u8]) -> std::io::Result<()> {
+    write.write_all(buffer).await?;
+    write.flush().await
+}
@@ -1,52 +1,157 @@
+use crate::client::ClientRef;
+use crate::crypt::ResyncCause;
 use crate::error::DecryptError;
 use crate::message::ClientMessage;
+use crate::proto::{legacy_version_to_v2, mumble_udp};
+use crate::server::udp_reader::UdpReader;
 use crate::state::ServerStateRef;
-use crate::voice::VoicePacket;
+use crate::voice::{ServerBound, VoicePacket, PROTOBUF_PING};
 
 use anyhow::anyhow;
 
 use byteorder::{ReadBytesExt, WriteBytesExt};
-use bytes::BytesMut;
+use bytes::{BufMut, Bytes, BytesMut};
+use protobuf::Message;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 use tokio_util::sync::CancellationToken;
 
-use super::constants::{MAX_BANDWIDTH_IN_BITS, MAX_CLIENTS};
+/// Binds the udp sockets of the server, with more than one socket they share the address with
+/// SO_REUSEPORT so the kernel spreads the clients between them, the datagrams of a client are always
+/// received by the same socket.
+pub fn bind_udp_sockets(addr: SocketAddr, count: usize) -> std::io::Result<Vec<Arc<UdpSocket>>> {
+    #[cfg(not(unix))]
+    let count = {
+        if count > 1 {
+            tracing::warn!("SO_REUSEPORT is not supported on this platform, using a single udp socket");
+        }
+
+        1
+    };
+
+    (0..count.max(1))
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+            #[cfg(unix)]
+            if count > 1 {
+                socket.set_reuse_port(true)?;
+            }
 
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+
+            Ok(Arc::new(UdpSocket::from_std(socket.into())?))
+        })
+        .collect()
+}
+
+/// Packets from unknown addresses waiting to be matched to a client, packets received while the
+/// queue is full are dropped
+const UNKNOWN_QUEUE_CAPACITY: usize = 256;
+/// Matching a packet decrypts it with the key of every client without a udp address, so it's
+/// limited to this many packets per second and socket
+const UNKNOWN_PACKETS_PER_SECOND: u32 = 200;
+
+type UnknownPacket = (BytesMut, SocketAddr);
+
+/// Receive loop of one udp socket, the packets of known clients are handled inline so the packets
+/// of a client are processed in order, the packets of unknown addresses are matched to a client by
+/// a separate task
 pub async fn create_udp_server(protocol_version: u32, socket: Arc<UdpSocket>, state: ServerStateRef, cancel_token: CancellationToken) {
+    let mut reader = UdpReader::default();
+    let (unknown_sender, unknown_receiver) = mpsc::channel(UNKNOWN_QUEUE_CAPACITY);
+
+    // stops once the sender is dropped at the end of the loop
+    tokio::spawn(handle_unknown_packets(unknown_receiver, state.clone()));
+
     loop {
-        match udp_server_run(protocol_version, socket.clone(), state.clone()).await {
-            Ok(_) => (),
-            Err(e) => tracing::error!("udp server error: {:?}", e),
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            result = udp_server_run(protocol_version, &socket, &state, &mut reader, &unknown_sender) => {
+                if let Err(e) = result {
+                    tracing::error!("udp server error: {:?}", e);
+                }
+            }
         }
     }
 }
 
-async fn udp_server_run(protocol_version: u32, socket: Arc<UdpSocket>, state: ServerStateRef) -> Result<(), anyhow::Error> {
-    let mut buffer = BytesMut::zeroed(1024);
-    if let Ok((size, addr)) = socket.recv_from(&mut buffer).await {
-        buffer.resize(size, 0);
+async fn udp_server_run(
+    protocol_version: u32,
+    socket: &UdpSocket,
+    state: &ServerStateRef,
+    reader: &mut UdpReader,
+    unknown_sender: &mpsc::Sender<UnknownPacket>,
+) -> Result<(), anyhow::Error> {
+    for (buffer, addr) in reader.recv(socket).await? {
+        let size = buffer.len();
 
-        tokio::spawn(async move {
-            match handle_packet(buffer, size, addr, protocol_version, socket, state).await {
-                Ok(_) => (),
-                Err(e) => tracing::error!("udp server handle packet error: {:?}", e),
-            }
-        });
+        if let Err(e) = handle_packet(buffer, size, addr, protocol_version, state, unknown_sender).await {
+            tracing::error!("udp server handle packet error: {:?}", e);
+        }
     }
 
     Ok(())
 }
 
+/// Matches the packets of unknown addresses to the client whose key decrypts them, at most
+/// `UNKNOWN_PACKETS_PER_SECOND` per second so spoofed packets can't stall the server
+async fn handle_unknown_packets(mut receiver: mpsc::Receiver<UnknownPacket>, state: ServerStateRef) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / UNKNOWN_PACKETS_PER_SECOND);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    while let Some((mut buffer, addr)) = receiver.recv().await {
+        let size = buffer.len();
+
+        // an earlier packet of the queue may have matched the client
+        let result = if let Some(client) = state.get_client_by_socket(&addr).await {
+            handle_client_packet(client, buffer, size, addr, &state).await
+        } else {
+            interval.tick().await;
+
+            match state.find_client_with_decrypt(&mut buffer, addr).await {
+                Ok(Some((client, packet))) => {
+                    tracing::info!("UPD connected client {} on {}", client, addr);
+
+                    handle_voice_packet(client, packet, size, addr, &state).await
+                }
+                Ok(None) => {
+                    tracing::error!("unknown client from address {}", addr);
+
+                    crate::metrics::UNKNOWN_MESSAGES_TOTAL
+                        .with_label_values(&["udp", "input", "UnknownPackets"])
+                        .inc();
+
+                    crate::metrics::UNKNOWN_MESSAGES_BYTES
+                        .with_label_values(&["udp", "input", "UnknownPacket"])
+                        .inc_by(size as u64);
+
+                    Ok(())
+                }
+                Err(e) => Err(e.into()),
+            }
+        };
+
+        if let Err(e) = result {
+            tracing::error!("udp server handle packet error: {:?}", e);
+        }
+    }
+}
+
 async fn handle_packet(
-    mut buffer: BytesMut,
+    buffer: BytesMut,
     size: usize,
     addr: SocketAddr,
     protocol_version: u32,
-    socket: Arc<UdpSocket>,
-    state: ServerStateRef,
+    state: &ServerStateRef,
+    unknown_sender: &mpsc::Sender<UnknownPacket>,
 ) -> Result<(), anyhow::Error> {
     if size <= 1 {
         return Err(anyhow!("Invalid packet"));
@@ -54,11 +159,10 @@ async fn handle_packet(
     let mut cursor = Cursor::new(&buffer[..size]);
     let kind = cursor.read_u32::<byteorder::LittleEndian>()?;
 
-    // respond to the server list ping packet
+    // respond to the legacy server list ping packet
     if size == 12 && kind == 0 {
         let timestamp = cursor.read_u64::<byteorder::LittleEndian>()?;
 
-        // TODO: actually read version and follow the mumble spec for using UDP protobufs here
         let mut send = Cursor::new(vec![0u8; 24]);
         // server version
         send.write_u32::<byteorder::BigEndian>(protocol_version)?;
@@ -67,11 +171,12 @@ async fn handle_packet(
         // user count
         send.write_u32::<byteorder::BigEndian>(state.clients.len() as u32)?;
         // max user count
-        send.write_u32::<byteorder::BigEndian>(MAX_CLIENTS as u32)?;
+        send.write_u32::<byteorder::BigEndian>(state.config.max_clients as u32)?;
         // max bandwidth per user
-        send.write_u32::<byteorder::BigEndian>(MAX_BANDWIDTH_IN_BITS)?;
+        send.write_u32::<byteorder::BigEndian>(state.config.max_bandwidth)?;
 
-        socket.send_to(send.get_ref().as_slice(), addr).await?;
+        // replies go through the udp writer so a slow send never stalls the receive loop
+        state.udp_writer.send(addr, Bytes::from(send.into_inner()))?;
 
         crate::metrics::MESSAGES_TOTAL
             .with_label_values(&["udp", "input", "PingAnonymous"])
@@ -96,79 +201,95 @@ async fn handle_packet(
 
     let client_opt = state.get_client_by_socket(&addr).await;
 
-    let (client, packet) = match client_opt {
-        Some(client) => {
-            // Send decrypt packet
-
-            let decrypt_result = {
-                let mut crypt_state = client.crypt_state.lock().await;
-                crypt_state.decrypt(&mut buffer)
-            };
+    // respond to the server list ping packet of 1.5 clients, which use the protobuf format and ask
+    // for the extended information, packets of known clients are always encrypted so we skip them
+    if client_opt.is_none() && buffer[0] == PROTOBUF_PING {
+        if let Ok(ping) = mumble_udp::Ping::parse_from_bytes(&buffer[1..size]) {
+            if ping.get_request_extended_information() {
+                return send_extended_ping(ping.get_timestamp(), size, addr, protocol_version, state);
+            }
+        }
+    }
 
-            match decrypt_result {
-                Ok(p) => (client, p),
-                Err(err) => {
-                    tracing::warn!("client {} decrypt error: {}", client, err);
+    match client_opt {
+        Some(client) => handle_client_packet(client, buffer, size, addr, state).await,
+        None => {
+            if unknown_sender.try_send((buffer, addr)).is_err() {
+                crate::metrics::UNKNOWN_MESSAGES_TOTAL
+                    .with_label_values(&["udp", "input", "DroppedUnknownPackets"])
+                    .inc();
+            }
 
-                    crate::metrics::MESSAGES_TOTAL
-                        .with_label_values(&["udp", "input", "VoicePacket"])
-                        .inc();
+            Ok(())
+        }
+    }
+}
 
-                    crate::metrics::MESSAGES_BYTES
-                        .with_label_values(&["udp", "input", "VoicePacket"])
-                        .inc_by(size as u64);
+/// Decrypts and handles a packet of a client whose udp address is known
+async fn handle_client_packet(
+    client: ClientRef,
+    mut buffer: BytesMut,
+    size: usize,
+    addr: SocketAddr,
+    state: &ServerStateRef,
+) -> Result<(), anyhow::Error> {
+    let decrypt_result = {
+        let mut crypt_state = client.crypt_state.lock();
+        crypt_state.decrypt(client.voice_protocol, &mut buffer)
+    };
 
-                    let restart_crypt = match err {
-                        DecryptError::Late => {
-                            let late = { client.crypt_state.lock().await.late };
+    let packet = match decrypt_result {
+        Ok(p) => p,
+        Err(err) => {
+            tracing::warn!("client {} decrypt error: {}", client, err);
 
-                            late > 100
-                        }
-                        DecryptError::Repeat => false,
-                        _ => true,
-                    };
+            crate::metrics::MESSAGES_TOTAL
+                .with_label_values(&["udp", "input", "VoicePacket"])
+                .inc();
 
-                    if restart_crypt {
-                        tracing::error!("client {} udp decrypt error: {}, reset crypt setup", client, err);
+            crate::metrics::MESSAGES_BYTES
+                .with_label_values(&["udp", "input", "VoicePacket"])
+                .inc_by(size as u64);
 
-                        if let Err(e) = state.reset_client_crypt(&client).await {
-                            tracing::error!("failed to send crypt setup: {:?}", e);
-                        }
-                    }
+            let restart_crypt = match err {
+                DecryptError::Late => {
+                    let late = { client.crypt_state.lock().late };
 
-                    return Ok(());
+                    (late > 100).then_some(ResyncCause::LateOverflow)
                 }
-            }
-        }
-        None => {
-            if let Some((client, packet)) = state.find_client_with_decrypt(&mut buffer, addr).await? {
-                tracing::info!("UPD connected client {} on {}", client, addr);
-
-                (client, packet)
-            } else {
-                // don't log if we've done it recently
-                // if let Ok(Some((_, _))) = state.logs.put(addr, ()) {
-                tracing::error!("unknown client from address {}", addr);
-                // }
-
-                crate::metrics::UNKNOWN_MESSAGES_TOTAL
-                    .with_label_values(&["udp", "input", "UnknownPackets"])
-                    .inc();
+                DecryptError::Repeat => None,
+                _ => Some(ResyncCause::DecryptFailure),
+            };
 
-                crate::metrics::UNKNOWN_MESSAGES_BYTES
-                    .with_label_values(&["udp", "input", "UnknownPacket"])
-                    .inc_by(size as u64);
+            if let Some(cause) = restart_crypt {
+                tracing::error!("client {} udp decrypt error: {}, resync crypt", client, err);
 
-                return Ok(());
+                if let Err(e) = state.resync_client_crypt(&client, cause).await {
+                    tracing::error!("failed to send crypt setup: {:?}", e);
+                }
             }
+
+            return Ok(());
         }
     };
 
+    handle_voice_packet(client, packet, size, addr, state).await
+}
+
+async fn handle_voice_packet(
+    client: ClientRef,
+    packet: VoicePacket<ServerBound>,
+    size: usize,
+    addr: SocketAddr,
+    state: &ServerStateRef,
+) -> Result<(), anyhow::Error> {
     let session_id = client.session_id;
     let client_packet = packet.into_client_bound(session_id);
 
     match &client_packet {
         VoicePacket::Ping { .. } => {
+            client.last_udp_ping.store(Instant::now());
+
             crate::metrics::MESSAGES_TOTAL
                 .with_label_values(&["udp", "input", "VoicePing"])
                 .inc();
@@ -180,13 +301,13 @@ async fn handle_packet(
             let mut dest = BytesMut::new();
 
             {
-                let mut crypt = client.crypt_state.lock().await;
-                crypt.encrypt(&client_packet, &mut dest);
+                let mut crypt = client.crypt_state.lock();
+                crypt.encrypt(client.voice_protocol, &client_packet, &mut dest)?;
             }
 
-            let buf = &dest.freeze()[..];
+            let len = dest.len();
 
-            match socket.send_to(buf, addr).await {
+            match state.udp_writer.send(addr, dest.freeze()) {
                 Ok(_) => {
                     crate::metrics::MESSAGES_TOTAL
                         .with_label_values(&["udp", "output", "VoicePing"])
@@ -194,7 +315,7 @@ async fn handle_packet(
 
                     crate::metrics::MESSAGES_BYTES
                         .with_label_values(&["udp", "output", "VoicePing"])
-                        .inc_by(buf.len() as u64);
+                        .inc_by(len as u64);
                 }
                 Err(err) => {
                     tracing::error!("cannot send ping udp packet: {}", err);
@@ -210,7 +331,9 @@ async fn handle_packet(
                 .with_label_values(&["udp", "input", "VoicePacket"])
                 .inc_by(size as u64);
 
-            let send_client_packet = { client.publisher.try_send(ClientMessage::RouteVoicePacket(client_packet)) };
+            client.record_voice(size);
+
+            let send_client_packet = { client.publisher.send(ClientMessage::RouteVoicePacket(client_packet)) };
 
             match send_client_packet {
                 Ok(_) => (),
@@ -223,3 +346,36 @@ async fn handle_packet(
 
     Ok(())
 }
+
+fn send_extended_ping(
+    timestamp: u64,
+    size: usize,
+    addr: SocketAddr,
+    protocol_version: u32,
+    state: &ServerStateRef,
+) -> Result<(), anyhow::Error> {
+    let mut ping = mumble_udp::Ping::new();
+    ping.set_timestamp(timestamp);
+    ping.set_server_version_v2(legacy_version_to_v2(protocol_version));
+    ping.set_user_count(state.clients.len() as u32);
+    ping.set_max_user_count(state.config.max_clients as u32);
+    ping.set_max_bandwidth_per_user(state.config.max_bandwidth);
+    // MumbleUDP.Ping has no field for the features of the server, they are sent in the
+    // ServerConfig message once the client is connected
+
+    let mut send = BytesMut::new();
+    send.put_u8(PROTOBUF_PING);
+    send.put_slice(&ping.write_to_bytes()?);
+
+    state.udp_writer.send(addr, send.freeze())?;
+
+    crate::metrics::MESSAGES_TOTAL
+        .with_label_values(&["udp", "input", "PingAnonymous"])
+        .inc();
+
+    crate::metrics::MESSAGES_BYTES
+        .with_label_values(&["udp", "input", "PingAnonymous"])
+        .inc_by(size as u64);
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use bytes::BytesMut;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Maximum amount of datagrams received with a single system call
+#[cfg(target_os = "linux")]
+const BATCH_SIZE: usize = 32;
+#[cfg(not(target_os = "linux"))]
+const BATCH_SIZE: usize = 1;
+
+/// Maximum size of a mumble udp packet, larger datagrams are truncated
+const PACKET_SIZE: usize = 1024;
+
+/// Receives the datagrams of a udp socket, in batches with a single `recvmmsg` call on linux.
+pub struct UdpReader {
+    buffers: Vec<u8>,
+    #[cfg(target_os = "linux")]
+    addrs: Vec<libc::sockaddr_storage>,
+    /// Slot in the buffers, size and sender of the received datagrams
+    received: Vec<(usize, usize, SocketAddr)>,
+}
+
+impl Default for UdpReader {
+    fn default() -> Self {
+        Self {
+            buffers: vec![0; BATCH_SIZE * PACKET_SIZE],
+            // SAFETY: sockaddr_storage is a plain C struct, all zeroes is a valid empty address
+            #[cfg(target_os = "linux")]
+            addrs: vec![unsafe { std::mem::zeroed() }; BATCH_SIZE],
+            received: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+}
+
+impl UdpReader {
+    /// Waits for datagrams and returns all the ones received, with the address of their sender
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<impl Iterator<Item = (BytesMut, SocketAddr)> + '_> {
+        self.received.clear();
+
+        #[cfg(target_os = "linux")]
+        {
+            use tokio::io::Interest;
+
+            socket
+                .async_io(Interest::READABLE, || {
+                    recvmmsg(socket, &mut self.buffers, &mut self.addrs, &mut self.received)
+                })
+                .await?;
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (size, addr) = socket.recv_from(&mut self.buffers).await?;
+
+            self.received.push((0, size, addr));
+        }
+
+        Ok(self.received.iter().map(|(slot, size, addr)| {
+            let start = slot * PACKET_SIZE;
+
+            (BytesMut::from(&self.buffers[start..start + size]), *addr)
+        }))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn recvmmsg(
+    socket: &UdpSocket,
+    buffers: &mut [u8],
+    addrs: &mut [libc::sockaddr_storage],
+    received: &mut Vec<(usize, usize, SocketAddr)>,
+) -> io::Result<()> {
+    use socket2::SockAddr;
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: iovec and mmsghdr are plain C structs, all zeroes is a valid empty value
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut messages: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+    for (index, (buffer, addr)) in buffers.chunks_exact_mut(PACKET_SIZE).zip(addrs.iter_mut()).enumerate() {
+        iovecs[index].iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+        iovecs[index].iov_len = PACKET_SIZE;
+
+        messages[index].msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        messages[index].msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        messages[index].msg_hdr.msg_iov = &mut iovecs[index];
+        messages[index].msg_hdr.msg_iovlen = 1;
+    }
+
+    // SAFETY: the headers point to the buffers and addresses given by the caller, which outlive the
+    // call, and to the iovecs above
+    let count = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            messages.as_mut_ptr(),
+            BATCH_SIZE as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (slot, (message, addr)) in messages.iter().zip(addrs.iter()).take(count as usize).enumerate() {
+        // SAFETY: the address was written by the kernel with the returned length
+        let addr = unsafe { SockAddr::new(*addr, message.msg_hdr.msg_namelen) };
+
+        if let Some(addr) = addr.as_socket() {
+            received.push((slot, (message.msg_len as usize).min(PACKET_SIZE), addr));
+        }
+    }
+
+    Ok(())
+}
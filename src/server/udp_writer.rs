@@ -0,0 +1,116 @@
+use crate::error::MumbleError;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Maximum amount of datagrams sent with a single system call
+const BATCH_SIZE: usize = 64;
+
+/// Amount of datagrams waiting to be sent, new datagrams are discarded when the writer can't keep up
+const QUEUE_SIZE: usize = 8192;
+
+/// Handle to the task sending the voice datagrams of every client on the udp socket.
+///
+/// The datagrams queued while the task is sending are sent together, with a single `sendmmsg` call
+/// on linux.
+#[derive(Clone)]
+pub struct UdpWriter {
+    sender: Sender<(SocketAddr, Bytes)>,
+}
+
+impl UdpWriter {
+    pub fn spawn(socket: Arc<UdpSocket>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+        tokio::spawn(write_loop(socket, receiver));
+
+        Self { sender }
+    }
+
+    /// Queues the datagram, it's discarded if the queue is full
+    pub fn send(&self, addr: SocketAddr, datagram: Bytes) -> Result<(), MumbleError> {
+        self.sender.try_send((addr, datagram)).map_err(|_| MumbleError::PacketDiscarded)
+    }
+}
+
+async fn write_loop(socket: Arc<UdpSocket>, mut receiver: Receiver<(SocketAddr, Bytes)>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    while receiver.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+        send_batch(&socket, &batch).await;
+
+        batch.clear();
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn send_batch(socket: &UdpSocket, batch: &[(SocketAddr, Bytes)]) {
+    use tokio::io::Interest;
+
+    let mut sent = 0;
+
+    while sent < batch.len() {
+        match socket.async_io(Interest::WRITABLE, || sendmmsg(socket, &batch[sent..])).await {
+            Ok(count) => sent += count,
+            Err(e) => {
+                // the first datagram of the batch was refused, skip it and send the others
+                tracing::error!("cannot send udp packet to {}: {}", batch[sent].0, e);
+
+                sent += 1;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendmmsg(socket: &UdpSocket, batch: &[(SocketAddr, Bytes)]) -> std::io::Result<usize> {
+    use socket2::SockAddr;
+    use std::os::fd::AsRawFd;
+
+    let addrs: Vec<SockAddr> = batch.iter().map(|(addr, _)| SockAddr::from(*addr)).collect();
+    let mut iovecs: Vec<libc::iovec> = batch
+        .iter()
+        .map(|(_, datagram)| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+
+    let mut messages: Vec<libc::mmsghdr> = addrs
+        .iter()
+        .zip(iovecs.iter_mut())
+        .map(|(addr, iovec)| {
+            // SAFETY: msghdr is a plain C struct, all zeroes is a valid empty header
+            let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+            header.msg_name = addr.as_ptr() as *mut libc::c_void;
+            header.msg_namelen = addr.len();
+            header.msg_iov = iovec;
+            header.msg_iovlen = 1;
+
+            libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    // SAFETY: the headers point to the addresses and datagrams of the batch, which outlive the call
+    let count = unsafe { libc::sendmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), messages.len() as libc::c_uint, 0) };
+
+    if count < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(count as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_batch(socket: &UdpSocket, batch: &[(SocketAddr, Bytes)]) {
+    for (addr, datagram) in batch {
+        if let Err(e) = socket.send_to(datagram, addr).await {
+            tracing::error!("cannot send udp packet to {}: {}", addr, e);
+        }
+    }
+}
@@ -1,21 +1,25 @@
 use crate::channel::{Channel, ChannelRef};
-use crate::client::{Client, ClientRef};
-use crate::crypt::CryptState;
+use crate::client::{Client, ClientConnection, ClientRef};
+use crate::config::Config;
+use crate::crypt::{CryptState, ResyncCause};
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
-use crate::proto::mumble::{Authenticate, ChannelRemove, ChannelState, CodecVersion, UserRemove, Version};
+use crate::group::VoiceGroups;
+use crate::message::{ClientMessage, ClientQueue};
+use crate::names::ClientNames;
+use crate::proto::mumble::{Authenticate, ChannelRemove, ChannelState, CodecVersion, UserRemove, UserState, Version};
 use crate::proto::{message_to_bytes, MessageKind};
-use crate::server::constants::MAX_CLIENTS;
-use crate::voice::{ServerBound, VoicePacket};
+use crate::server::{TcpWriter, UdpWriter};
+use crate::target::VoiceTarget;
+use crate::voice::{ServerBound, VoicePacket, VoiceProtocol};
 use bytes::BytesMut;
 use protobuf::Message;
 use scc::{HashCache, HashMap};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, WriteHalf};
+use std::time::{Duration, Instant};
+use tokio::io::WriteHalf;
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc::Sender;
 use tokio_rustls::server::TlsStream;
 
 pub struct CodecState {
@@ -62,31 +66,40 @@ pub struct ServerState {
     pub clients: HashMap<u32, ClientRef>,
     pub clients_without_udp: HashMap<u32, ClientRef>,
     pub clients_by_socket: HashMap<SocketAddr, ClientRef>,
+    /// Sessions of the clients by name for the http api
+    pub clients_by_name: ClientNames,
     // pub clients_by_peer: HashMap<IpAddr, AtomicU32>,
     pub channels: HashMap<u32, ChannelRef>,
+    pub radios: VoiceGroups,
+    pub calls: VoiceGroups,
     pub codec_state: Arc<CodecState>,
-    pub socket: Arc<UdpSocket>,
+    pub config: Config,
+    pub udp_writer: UdpWriter,
     pub logs: HashCache<SocketAddr, ()>,
     session_count: AtomicU32,
     channel_count: AtomicU32,
 }
 
 impl ServerState {
-    pub fn new(socket: Arc<UdpSocket>) -> Self {
+    pub fn new(config: Config, socket: Arc<UdpSocket>) -> Self {
         let channels = HashMap::new();
         channels.upsert(0, Channel::new(0, Some(0), "Root".to_string(), "Root channel".to_string(), false));
 
         Self {
             // we preallocate the maximum amount of clients to prevent the possibility of resizes
             // later, which will prevent double-sends in certain situations
-            clients: HashMap::with_capacity(MAX_CLIENTS),
+            clients: HashMap::with_capacity(config.max_clients),
             logs: HashCache::with_capacity(500, 1000),
-            clients_without_udp: HashMap::with_capacity(MAX_CLIENTS),
-            clients_by_socket: HashMap::with_capacity(MAX_CLIENTS),
+            clients_without_udp: HashMap::with_capacity(config.max_clients),
+            clients_by_socket: HashMap::with_capacity(config.max_clients),
+            clients_by_name: ClientNames::with_capacity(config.max_clients),
             // clients_by_peer: HashMap::with_capacity(MAX_CLIENTS),
             channels,
+            radios: VoiceGroups::default(),
+            calls: VoiceGroups::default(),
             codec_state: Arc::new(CodecState::default()),
-            socket,
+            config,
+            udp_writer: UdpWriter::spawn(socket),
             session_count: AtomicU32::new(1),
             channel_count: AtomicU32::new(1),
         }
@@ -95,23 +108,36 @@ impl ServerState {
     pub async fn add_client(
         &self,
         version: Version,
+        voice_protocol: VoiceProtocol,
         authenticate: Authenticate,
         crypt_state: CryptState,
         write: WriteHalf<TlsStream<TcpStream>>,
-        publisher: Sender<ClientMessage>,
-        _peer_ip: IpAddr,
+        peer_ip: IpAddr,
     ) -> ClientRef {
         let session_id = self.get_free_session_id();
+        let writer = TcpWriter::spawn(
+            write,
+            Duration::from_millis(self.config.tcp.write_timeout_ms),
+            // the initial sync sends the state of every channel and client at once
+            self.channels.len() + self.config.max_clients,
+            format!("{} [session id: {}]", authenticate.get_username(), session_id),
+        );
 
-        let client = Client::new(
+        let connection = ClientConnection {
             version,
+            voice_protocol,
             authenticate,
+            crypt_state,
+            writer,
+            peer_ip,
+        };
+
+        let client = Client::new(
+            connection,
             session_id,
             0,
-            crypt_state,
-            write,
-            Arc::clone(&self.socket),
-            publisher,
+            self.udp_writer.clone(),
+            ClientQueue::new(self.config.client_queue.voice_capacity),
         );
 
         crate::metrics::CLIENTS_TOTAL.inc();
@@ -123,6 +149,7 @@ impl ServerState {
         // }
 
         self.clients_without_udp.upsert_async(session_id, Arc::clone(&client)).await;
+        self.clients_by_name.insert(client.get_name().as_str(), session_id).await;
 
         client
     }
@@ -145,16 +172,9 @@ impl ServerState {
     }
 
     pub async fn get_client_by_name(&self, name: &str) -> Option<ClientRef> {
-        let client = self
-            .clients
-            .any_entry_async(|_k, client| client.authenticate.get_username() == name)
-            .await;
-
-        if let Some(cl) = client {
-            return Some(Arc::clone(cl.get()));
-        }
+        let session_id = self.clients_by_name.get(name).await?;
 
-        None
+        self.clients.get_async(&session_id).await.map(|client| Arc::clone(client.get()))
     }
 
     pub async fn set_client_socket(&self, client: &ClientRef, addr: SocketAddr) {
@@ -174,7 +194,7 @@ impl ServerState {
         let bytes = Arc::new(bytes);
 
         self.clients.scan(|_k, client| {
-            match client.publisher.try_send(ClientMessage::SendMessage {
+            match client.publisher.send(ClientMessage::SendMessage {
                 kind,
                 payload: Arc::clone(&bytes),
             }) {
@@ -189,11 +209,18 @@ impl ServerState {
     }
 
     async fn handle_client_left_channel(&self, client_session: u32, leave_channel_id: u32) -> Option<u32> {
-        if let Some(channel) = self.channels.get_async(&leave_channel_id).await {
+        let channel = self
+            .channels
+            .get_async(&leave_channel_id)
+            .await
+            .map(|channel| Arc::clone(channel.get()));
+
+        if let Some(channel) = &channel {
             // remove the client from the channel
             channel.clients.remove_async(&client_session).await;
+            self.refresh_channel(channel).await;
 
-            channel.parent_id?;;
+            channel.parent_id?;
 
             // if the channel isn't temporary then we want to keep it
             if !channel.temporary || !channel.get_clients().is_empty() {
@@ -207,6 +234,11 @@ impl ServerState {
 
         self.channels.remove_async(&leave_channel_id).await;
 
+        // the voice targets including the channel no longer reach its listeners
+        if let Some(channel) = &channel {
+            self.refresh_targets(&channel.targeted_by).await;
+        }
+
         match self.broadcast_message(MessageKind::ChannelRemove, &channel_remove) {
             Ok(_) => (),
             Err(e) => tracing::error!("failed to send channel remove: {:?}", e),
@@ -215,6 +247,26 @@ impl ServerState {
         Some(leave_channel_id)
     }
 
+    /// Applies a user state change made by the server (http api) to the client and broadcasts it to
+    /// every client
+    pub async fn update_user_state(&self, client: &ClientRef, user_state: &UserState) {
+        let could_hear = client.can_hear();
+        let changes = client.update(user_state, true);
+
+        if client.can_hear() != could_hear {
+            self.refresh_client(client).await;
+        }
+
+        if let Some(mut changes) = changes {
+            // session ids start at 1, the actor 0 is the server itself
+            changes.set_actor(0);
+
+            if let Err(e) = self.broadcast_message(MessageKind::UserState, &changes) {
+                tracing::error!("failed to send user state: {:?}", e);
+            }
+        }
+    }
+
     pub async fn set_client_channel(&self, client: &ClientRef, channel: u32) -> Result<(), MumbleError> {
         let leave_channel_id = client.join_channel(channel);
 
@@ -225,8 +277,9 @@ impl ServerState {
             leave_channel_id
         );
 
-        if let Some(channel) = self.channels.get_async(&channel).await {
-            channel.clients.upsert_async(client.session_id, Arc::clone(client)).await;
+        if let Some(new_channel) = self.channels.get_async(&channel).await.map(|channel| Arc::clone(channel.get())) {
+            new_channel.clients.upsert_async(client.session_id, Arc::clone(client)).await;
+            self.refresh_channel(&new_channel).await;
         } else {
             return Err(MumbleError::ChannelDoesntExist);
         }
@@ -249,6 +302,135 @@ impl ServerState {
         Ok(())
     }
 
+    /// Rebuilds the recipients of the channel and of the voice targets including it, must be called
+    /// after any change of its clients or listeners
+    pub async fn refresh_channel(&self, channel: &Channel) {
+        channel.refresh_recipients().await;
+
+        self.refresh_targets(&channel.targeted_by).await;
+    }
+
+    async fn refresh_targets(&self, targets: &HashMap<(u32, u8), ()>) {
+        let mut ids = Vec::new();
+        targets.scan_async(|id, _| ids.push(*id)).await;
+
+        for (session_id, target_id) in ids {
+            self.refresh_target(session_id, target_id).await;
+        }
+    }
+
+    /// Rebuilds the recipients of a voice target from its sessions and channels
+    pub async fn refresh_target(&self, session_id: u32, target_id: u8) {
+        let target = self
+            .clients
+            .read_async(&session_id, |_, client| client.get_target(target_id))
+            .await
+            .flatten();
+
+        let Some(target) = target else {
+            return;
+        };
+
+        let generation = target.recipients.start_refresh();
+        let mut recipients = std::collections::HashMap::new();
+
+        let mut sessions = Vec::new();
+        target.sessions.scan_async(|session_id, _| sessions.push(*session_id)).await;
+
+        for session_id in sessions {
+            if let Some(client) = self.clients.get_async(&session_id).await {
+                if client.can_hear() {
+                    recipients.insert(session_id, Arc::clone(client.get()));
+                }
+            }
+        }
+
+        let mut channels = Vec::new();
+        target.channels.scan_async(|channel_id, _| channels.push(*channel_id)).await;
+
+        for channel_id in channels {
+            if let Some(channel) = self.channels.get_async(&channel_id).await {
+                for client in channel.target_recipients.get().iter() {
+                    recipients.insert(client.session_id, Arc::clone(client));
+                }
+            }
+        }
+
+        target.recipients.set(generation, recipients.into_values().collect());
+    }
+
+    /// Rebuilds every recipients list including the client, after it started or stopped hearing
+    pub async fn refresh_client(&self, client: &ClientRef) {
+        let mut channels = Vec::new();
+
+        self.channels
+            .scan_async(|_, channel| {
+                if channel.clients.contains(&client.session_id) || channel.listeners.contains(&client.session_id) {
+                    channels.push(Arc::clone(channel));
+                }
+            })
+            .await;
+
+        for channel in channels {
+            self.refresh_channel(&channel).await;
+        }
+
+        self.refresh_targets(&client.targeted_by).await;
+
+        self.radios.refresh_client(client.session_id).await;
+        self.calls.refresh_client(client.session_id).await;
+    }
+
+    /// Registers the voice target on its sessions and channels, so it's refreshed when they change
+    pub async fn register_target(&self, session_id: u32, target_id: u8, target: &VoiceTarget) {
+        let mut sessions = Vec::new();
+        target.sessions.scan_async(|session_id, _| sessions.push(*session_id)).await;
+
+        for target_session_id in sessions {
+            self.clients
+                .read_async(&target_session_id, |_, client| {
+                    client.targeted_by.upsert((session_id, target_id), ());
+                })
+                .await;
+        }
+
+        let mut channels = Vec::new();
+        target.channels.scan_async(|channel_id, _| channels.push(*channel_id)).await;
+
+        for channel_id in channels {
+            self.channels
+                .read_async(&channel_id, |_, channel| {
+                    channel.targeted_by.upsert((session_id, target_id), ());
+                })
+                .await;
+        }
+    }
+
+    /// Reverts [Self::register_target], before the voice target is changed or its owner leaves
+    pub async fn unregister_target(&self, session_id: u32, target_id: u8, target: &VoiceTarget) {
+        let mut sessions = Vec::new();
+        target.sessions.scan_async(|session_id, _| sessions.push(*session_id)).await;
+
+        for target_session_id in sessions {
+            self.clients
+                .read_async(&target_session_id, |_, client| {
+                    client.targeted_by.remove(&(session_id, target_id));
+                })
+                .await;
+        }
+
+        let mut channels = Vec::new();
+        target.channels.scan_async(|channel_id, _| channels.push(*channel_id)).await;
+
+        for channel_id in channels {
+            self.channels
+                .read_async(&channel_id, |_, channel| {
+                    channel.targeted_by.remove(&(session_id, target_id));
+                })
+                .await;
+        }
+    }
+
     pub async fn get_channel_by_name(&self, name: &str) -> Option<ChannelRef> {
         let client = self.channels.any_entry_async(|_k, channel| channel.name == name).await;
 
@@ -275,30 +457,32 @@ impl ServerState {
         bytes: &mut BytesMut,
         addr: SocketAddr,
     ) -> Result<Option<(ClientRef, VoicePacket<ServerBound>)>, MumbleError> {
-        let mut client_and_packet = None;
+        let mut clients = Vec::new();
 
-        let mut iter = self.clients_without_udp.first_entry_async().await;
+        // collected first to not hold the entries while trying to decrypt
+        self.clients_without_udp
+            .scan_async(|_, client| clients.push(Arc::clone(client)))
+            .await;
+
+        let mut client_and_packet = None;
 
-        while let Some(client) = iter {
-            let c = client.get();
+        for client in clients {
             let mut try_buf = bytes.clone();
             let decrypt_result = {
-                let mut crypt_state = client.crypt_state.lock().await;
-                crypt_state.decrypt(&mut try_buf)
+                let mut crypt_state = client.crypt_state.lock();
+                crypt_state.decrypt(client.voice_protocol, &mut try_buf)
             };
 
             match decrypt_result {
                 Ok(p) => {
-                    self.set_client_socket(c, addr).await;
-                    client_and_packet = Some((Arc::clone(c), p));
+                    self.set_client_socket(&client, addr).await;
+                    client_and_packet = Some((client, p));
                     break;
                 }
                 Err(err) => {
                     tracing::debug!("failed to decrypt packet: {:?}, continue to next client", err);
                 }
             }
-
-            iter = client.next_async().await;
         }
 
         if let Some((client, _)) = &client_and_packet {
@@ -308,12 +492,47 @@ impl ServerState {
         Ok(client_and_packet)
     }
 
+    /// Resynchronizes the crypt state of the client, a failed resynchronization is retried with an
+    /// exponential backoff. The nonces are exchanged first, the key is only regenerated (see
+    /// `reset_client_crypt`) once `nonce_resyncs` attempts failed.
+    ///
+    /// NOTE: This shouldn't be called in an iterator for `client_by_socket` or else it will cause
+    /// a deadlock
+    pub async fn resync_client_crypt(&self, client: &ClientRef, cause: ResyncCause) -> Result<(), MumbleError> {
+        let config = &self.config.crypt;
+
+        let attempts = {
+            client.resync_backoff.lock().try_attempt(
+                Instant::now(),
+                Duration::from_millis(config.backoff_ms),
+                Duration::from_millis(config.max_backoff_ms),
+            )
+        };
+
+        let Some(attempts) = attempts else {
+            return Ok(());
+        };
+
+        if attempts >= config.nonce_resyncs {
+            tracing::info!("resetting {} crypt after {} failed resyncs ({})", client, attempts, cause.as_str());
+
+            return self.reset_client_crypt(client, cause).await;
+        }
+
+        tracing::info!("resyncing {} crypt nonces ({})", client, cause.as_str());
+
+        client.record_resync(cause);
+        client.send_crypt_resync().await
+    }
+
     /// NOTE: This shouldn't be called in an iterator for `client_by_socket` or else it will cause
     /// a deadlock
     ///
     /// Resets the clients crypt state and removes their udp socket so we no longer take invalid
     /// data from the UDP stream
-    pub async fn reset_client_crypt(&self, client: &ClientRef) -> Result<(), MumbleError> {
+    pub async fn reset_client_crypt(&self, client: &ClientRef, cause: ResyncCause) -> Result<(), MumbleError> {
+        client.record_resync(cause);
+
         self.clients_without_udp.upsert_async(client.session_id, Arc::clone(client)).await;
 
         // swap out the clients socket with none so we don't try to reuse the old socket
@@ -334,24 +553,49 @@ impl ServerState {
         self.clients_without_udp.remove_async(&client_session).await;
 
         // if the client was listening to any channels we want to remove them
+        let mut listened_channels = Vec::new();
+
         self.channels
             .scan_async(|_, channel| {
-                channel.listeners.retain(|session_id, _| *session_id != client_session);
+                if channel.listeners.remove(&client_session).is_some() {
+                    listened_channels.push(Arc::clone(channel));
+                }
             })
             .await;
 
+        for channel in listened_channels {
+            self.refresh_channel(&channel).await;
+        }
+
+        self.radios.remove_client(client_session).await;
+        self.calls.remove_client(client_session).await;
+
         if let Some((_, client)) = client {
             tracing::info!("Removing client {}", client);
 
+            // other clients may share the name
+            self.clients_by_name.remove(client.get_name().as_str(), client_session).await;
+
+            // the voice targets of other clients including this client no longer reach it, and its
+            // own voice targets are gone
+            self.refresh_targets(&client.targeted_by).await;
+
+            for (index, target) in client.targets.iter().enumerate() {
+                self.unregister_target(client_session, index as u8 + 1, target).await;
+            }
+
             // This is a hack to get the publisher out of its loop, if its already out of its loop
             // then we don't care and we can just ignore it
-            let _ = client.publisher.try_send(ClientMessage::Disconnect);
+            let _ = client.publisher.send(ClientMessage::Disconnect);
+
+            let session = client_session.to_string();
+            let _ = crate::metrics::CLIENT_QUEUE_DEPTH.remove_label_values(&[&session, "control"]);
+            let _ = crate::metrics::CLIENT_QUEUE_DEPTH.remove_label_values(&[&session, "voice"]);
+            let _ = crate::metrics::CLIENT_QUEUE_DROPPED.remove_label_values(&[&session]);
 
             // close the writer instantly so even if there's any References to client still, we will
             // still remove the socket as soon as we can.
-            {
-                let _ = client.write.lock().await.shutdown().await;
-            }
+            client.writer.close();
 
             let socket = client.udp_socket_addr.swap(None);
             // let mut should_remove = false;
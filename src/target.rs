@@ -1,7 +1,11 @@
 use scc::HashMap;
 
-#[derive(Default, Debug)]
+use crate::recipients::Recipients;
+
+#[derive(Default)]
 pub struct VoiceTarget {
     pub sessions: HashMap<u32, ()>,
     pub channels: HashMap<u32, ()>,
+    /// Clients of the sessions and channels of the target which can hear
+    pub recipients: Recipients,
 }
@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::UdpFallbackConfig;
+
+/// How the voice packets reach a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The client has no udp connection, its voice packets are tunneled through tcp
+    Tcp,
+    /// The voice packets are sent over udp
+    Udp,
+    /// The client has an udp connection but doesn't receive our udp packets, its voice packets are
+    /// tunneled through tcp until udp works again
+    Fallback,
+}
+
+impl Transport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Udp => "udp",
+            Transport::Fallback => "fallback",
+        }
+    }
+}
+
+/// Health of the udp packets sent to a client, from the packets it reports as received in its pings.
+///
+/// Udp can work one way only: the server receives the packets of the client but the client never
+/// receives ours. When the server keeps sending while the client reports no new packet, its voice
+/// packets are tunneled through tcp instead. The pings of the client are still answered over udp
+/// and probe the connection, udp is used again once the client receives them, or when the fallback
+/// expires (doubling each time udp fails again).
+pub struct UdpHealth {
+    fallback: AtomicBool,
+    state: parking_lot::Mutex<HealthState>,
+}
+
+struct HealthState {
+    sent: u32,
+    good: u32,
+    failed_reports: u32,
+    fallback_until: Instant,
+    fallback_delay: Option<Duration>,
+}
+
+impl Default for UdpHealth {
+    fn default() -> Self {
+        Self {
+            fallback: AtomicBool::new(false),
+            state: parking_lot::Mutex::new(HealthState {
+                sent: 0,
+                good: 0,
+                failed_reports: 0,
+                fallback_until: Instant::now(),
+                fallback_delay: None,
+            }),
+        }
+    }
+}
+
+impl UdpHealth {
+    /// Whether the voice packets of the client are tunneled through tcp while udp is broken
+    pub fn is_fallback(&self) -> bool {
+        self.fallback.load(Ordering::Relaxed)
+    }
+
+    /// Updates the health from the packets sent to the client and the packets it received (both
+    /// counted since the last crypt reset), returns the new transport when it changed
+    pub fn report(&self, sent: u32, good: u32, config: &UdpFallbackConfig) -> Option<Transport> {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+
+        // the counters restart from 0 when the crypt state is reset
+        let sent_delta = sent.checked_sub(state.sent).unwrap_or(sent);
+        let good_delta = good.checked_sub(state.good).unwrap_or(good);
+
+        state.sent = sent;
+        state.good = good;
+
+        if self.is_fallback() {
+            if good_delta == 0 && now < state.fallback_until {
+                return None;
+            }
+
+            // the client received our pings again, or it's time to probe udp with the voice packets
+            if good_delta > 0 {
+                state.fallback_delay = None;
+            }
+
+            state.failed_reports = 0;
+            self.fallback.store(false, Ordering::Relaxed);
+
+            return Some(Transport::Udp);
+        }
+
+        if sent_delta == 0 || good_delta > 0 {
+            if good_delta > 0 {
+                state.fallback_delay = None;
+            }
+
+            state.failed_reports = 0;
+
+            return None;
+        }
+
+        state.failed_reports += 1;
+
+        if state.failed_reports < config.failed_reports {
+            return None;
+        }
+
+        let delay = state
+            .fallback_delay
+            .map(|delay| delay.saturating_mul(2))
+            .unwrap_or(Duration::from_millis(config.probe_ms))
+            .min(Duration::from_millis(config.max_probe_ms));
+
+        state.failed_reports = 0;
+        state.fallback_delay = Some(delay);
+        state.fallback_until = now + delay;
+        self.fallback.store(true, Ordering::Relaxed);
+
+        Some(Transport::Fallback)
+    }
+}
@@ -27,45 +27,56 @@ impl<T: io::Read> ReadExt for T {
     fn read_varint(&mut self) -> io::Result<u64> {
         let b0 = self.read_u8()?;
         if b0 & 0b1111_1100 == 0b1111_1000 {
-            return Ok(!self.read_varint()?);
+            // a negated value can't be negated again, otherwise a packet full of prefixes would
+            // recurse once per byte
+            let b0 = self.read_u8()?;
+            if b0 & 0b1111_1100 == 0b1111_1000 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "nested negative varint"));
+            }
+            return Ok(!read_unsigned_varint(self, b0)?);
         }
-        if b0 & 0b1111_1100 == 0b1111_1100 {
-            return Ok(!u64::from(b0 & 0x03));
-        }
-        if (b0 & 0b1000_0000) == 0 {
-            return Ok(u64::from(b0 & 0b0111_1111));
-        }
-        let b1 = self.read_u8()?;
-        if (b0 & 0b0100_0000) == 0 {
-            return Ok(u64::from(b0 & 0b0011_1111) << 8 | u64::from(b1));
-        }
-        let b2 = self.read_u8()?;
-        if (b0 & 0b0010_0000) == 0 {
-            return Ok(u64::from(b0 & 0b0001_1111) << 16 | u64::from(b1) << 8 | u64::from(b2));
-        }
-        let b3 = self.read_u8()?;
-        if (b0 & 0b0001_0000) == 0 {
-            return Ok(u64::from(b0 & 0x0F) << 24 | u64::from(b1) << 16 | u64::from(b2) << 8 | u64::from(b3));
-        }
-        let b4 = self.read_u8()?;
-        if (b0 & 0b0000_0100) == 0 {
-            return Ok(u64::from(b1) << 24 | u64::from(b2) << 16 | u64::from(b3) << 8 | u64::from(b4));
-        }
-        let b5 = self.read_u8()?;
-        let b6 = self.read_u8()?;
-        let b7 = self.read_u8()?;
-        let b8 = self.read_u8()?;
-        Ok(u64::from(b1) << 56
-            | u64::from(b2) << 48
-            | u64::from(b3) << 40
-            | u64::from(b4) << 32
-            | u64::from(b5) << 24
-            | u64::from(b6) << 16
-            | u64::from(b7) << 8
-            | u64::from(b8))
+        read_unsigned_varint(self, b0)
     }
 }
 
+/// Reads the rest of a varint which doesn't start with the negative prefix
+fn read_unsigned_varint<T: io::Read + ?Sized>(reader: &mut T, b0: u8) -> io::Result<u64> {
+    if b0 & 0b1111_1100 == 0b1111_1100 {
+        return Ok(!u64::from(b0 & 0x03));
+    }
+    if (b0 & 0b1000_0000) == 0 {
+        return Ok(u64::from(b0 & 0b0111_1111));
+    }
+    let b1 = reader.read_u8()?;
+    if (b0 & 0b0100_0000) == 0 {
+        return Ok(u64::from(b0 & 0b0011_1111) << 8 | u64::from(b1));
+    }
+    let b2 = reader.read_u8()?;
+    if (b0 & 0b0010_0000) == 0 {
+        return Ok(u64::from(b0 & 0b0001_1111) << 16 | u64::from(b1) << 8 | u64::from(b2));
+    }
+    let b3 = reader.read_u8()?;
+    if (b0 & 0b0001_0000) == 0 {
+        return Ok(u64::from(b0 & 0x0F) << 24 | u64::from(b1) << 16 | u64::from(b2) << 8 | u64::from(b3));
+    }
+    let b4 = reader.read_u8()?;
+    if (b0 & 0b0000_0100) == 0 {
+        return Ok(u64::from(b1) << 24 | u64::from(b2) << 16 | u64::from(b3) << 8 | u64::from(b4));
+    }
+    let b5 = reader.read_u8()?;
+    let b6 = reader.read_u8()?;
+    let b7 = reader.read_u8()?;
+    let b8 = reader.read_u8()?;
+    Ok(u64::from(b1) << 56
+        | u64::from(b2) << 48
+        | u64::from(b3) << 40
+        | u64::from(b4) << 32
+        | u64::from(b5) << 24
+        | u64::from(b6) << 16
+        | u64::from(b7) << 8
+        | u64::from(b8))
+}
+
 impl<T: io::Write> WriteExt for T {
     fn write_varint(&mut self, value: u64) -> io::Result<()> {
         if value & 0xffff_ffff_ffff_fffc == 0xffff_ffff_ffff_fffc {
@@ -123,3 +134,101 @@ impl<T: BufMut> BufMutExt for T {
         self.writer().write_varint(val).expect("BufMut::writer never errors");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::io::Cursor;
+
+    fn encode(value: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_varint(value).unwrap();
+        buf
+    }
+
+    // examples from the varint section of the mumble protocol documentation
+    const VECTORS: &[(u64, &[u8])] = &[
+        (0, &[0x00]),
+        (0x7f, &[0x7f]),
+        (0x80, &[0x80, 0x80]),
+        (0x3fff, &[0xbf, 0xff]),
+        (0x4000, &[0xc0, 0x40, 0x00]),
+        (0x1f_ffff, &[0xdf, 0xff, 0xff]),
+        (0x20_0000, &[0xe0, 0x20, 0x00, 0x00]),
+        (0x0fff_ffff, &[0xef, 0xff, 0xff, 0xff]),
+        (0x1000_0000, &[0xf0, 0x10, 0x00, 0x00, 0x00]),
+        (0xffff_ffff, &[0xf0, 0xff, 0xff, 0xff, 0xff]),
+        (0x1_0000_0000, &[0xf4, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]),
+        (!0, &[0xfc]),
+        (!3, &[0xff]),
+        (!4, &[0xf8, 0x04]),
+        (!0x80, &[0xf8, 0x80, 0x80]),
+    ];
+
+    #[test]
+    fn known_vectors() {
+        for (value, bytes) in VECTORS {
+            assert_eq!(encode(*value), *bytes, "encoding {:#x}", value);
+            assert_eq!(Cursor::new(bytes).read_varint().unwrap(), *value, "decoding {:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn truncated_vectors() {
+        for (_, bytes) in VECTORS {
+            for len in 0..bytes.len() {
+                assert!(Cursor::new(&bytes[..len]).read_varint().is_err(), "decoding {:02x?}", &bytes[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn redundant_encodings() {
+        // the writer always uses the shortest form, but longer ones are valid
+        assert_eq!(Cursor::new([0x80, 0x05]).read_varint().unwrap(), 5);
+        assert_eq!(Cursor::new([0xf0, 0x00, 0x00, 0x00, 0x05]).read_varint().unwrap(), 5);
+        assert_eq!(Cursor::new([0xf8, 0xfc]).read_varint().unwrap(), 0);
+    }
+
+    #[test]
+    fn nested_negative_prefixes() {
+        assert!(Cursor::new([0xf8, 0xf8, 0x05]).read_varint().is_err());
+
+        let prefixes = vec![0xf8; 1 << 20];
+        assert!(Cursor::new(prefixes).read_varint().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(value: u64) {
+            let bytes = encode(value);
+            let mut cursor = Cursor::new(&bytes);
+
+            prop_assert_eq!(cursor.read_varint().unwrap(), value);
+            prop_assert_eq!(cursor.position() as usize, bytes.len());
+        }
+
+        #[test]
+        fn put_varint_matches_write_varint(value: u64) {
+            let mut buf = bytes::BytesMut::new();
+            buf.put_varint(value);
+
+            prop_assert_eq!(&buf[..], &encode(value)[..]);
+        }
+
+        #[test]
+        fn truncated_input_is_an_error(value: u64) {
+            let bytes = encode(value);
+
+            for len in 0..bytes.len() {
+                prop_assert!(Cursor::new(&bytes[..len]).read_varint().is_err());
+            }
+        }
+
+        #[test]
+        fn arbitrary_input_never_panics(bytes: Vec<u8>) {
+            let _ = Cursor::new(&bytes).read_varint();
+        }
+    }
+}
@@ -1,6 +1,8 @@
 //! Voice channel packets and codecs
 
-use crate::error::DecryptError;
+use crate::error::{DecryptError, MumbleError};
+use crate::proto::mumble::Version;
+use crate::proto::{get_version_v2, mumble_udp, PROTOBUF_UDP_VERSION};
 use byteorder::ReadBytesExt;
 use bytes::Buf;
 use bytes::BufMut;
@@ -10,12 +12,16 @@ use std::fmt::Debug;
 use std::io;
 use std::io::{Cursor, Read};
 use std::marker::PhantomData;
+use std::sync::OnceLock;
+
+use protobuf::wire_format::WireType;
+use protobuf::{CodedInputStream, Message};
 
 use super::varint::BufMutExt;
 use super::varint::ReadExt;
 
 /// A packet transmitted via Mumble's voice channel.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum VoicePacket<Dst: VoicePacketDst> {
     /// Ping packets contain opaque timestamp-like values which should simply be echoed back.
     Ping {
@@ -45,6 +51,10 @@ pub enum VoicePacket<Dst: VoicePacketDst> {
         /// receiving this packet can deal with such values (e.g. games with builtin Mumble
         /// client may use this field to transmit additional data to other game clients).
         position_info: Option<Bytes>,
+        /// Volume factor the receiving client should apply to the audio, set by the server.
+        ///
+        /// Only exists in the protobuf format, it's dropped when encoding legacy packets.
+        volume_adjustment: Option<f32>,
     },
 }
 
@@ -79,6 +89,10 @@ pub trait VoicePacketDst: Default + PartialEq {
     fn read_session_id<T: Read + Sized>(buf: &mut T) -> Result<Self::SessionId, io::Error>;
     /// Writes session id to packets traveling in this direction.
     fn write_session_id(buf: &mut BytesMut, session_id: &Self::SessionId);
+    /// Reads target and session id of protobuf audio messages traveling in this direction.
+    fn read_protobuf_header(audio: &mumble_udp::Audio) -> Result<(u8, Self::SessionId), DecryptError>;
+    /// Writes target and session id to protobuf audio messages traveling in this direction.
+    fn write_protobuf_header(audio: &mut mumble_udp::Audio, target: u8, session_id: &Self::SessionId);
 }
 
 impl VoicePacketDst for ServerBound {
@@ -89,6 +103,14 @@ impl VoicePacketDst for ServerBound {
     }
 
     fn write_session_id(_buf: &mut BytesMut, _session_id: &Self::SessionId) {}
+
+    fn read_protobuf_header(audio: &mumble_udp::Audio) -> Result<(u8, Self::SessionId), DecryptError> {
+        Ok((protobuf_target(audio.get_target())?, ()))
+    }
+
+    fn write_protobuf_header(audio: &mut mumble_udp::Audio, target: u8, _session_id: &Self::SessionId) {
+        audio.set_target(u32::from(target));
+    }
 }
 
 impl VoicePacketDst for ClientBound {
@@ -101,6 +123,134 @@ impl VoicePacketDst for ClientBound {
     fn write_session_id(buf: &mut BytesMut, session_id: &Self::SessionId) {
         buf.put_varint(u64::from(*session_id))
     }
+
+    fn read_protobuf_header(audio: &mumble_udp::Audio) -> Result<(u8, Self::SessionId), DecryptError> {
+        Ok((protobuf_target(audio.get_context())?, audio.get_sender_session()))
+    }
+
+    fn write_protobuf_header(audio: &mut mumble_udp::Audio, target: u8, session_id: &Self::SessionId) {
+        // client bound packets carry the context of the audio instead of the target, which uses the
+        // same values as the legacy format (0 for normal talking, 1 for shout, 2 for whisper...)
+        audio.set_context(u32::from(target));
+        audio.set_sender_session(*session_id);
+    }
+}
+
+/// Only targets 0-31 are valid, anything else can't be represented by [VoicePacket]
+fn protobuf_target(target: u32) -> Result<u8, DecryptError> {
+    if target > 0b11111 {
+        return Err(DecryptError::Io(io::Error::new(io::ErrorKind::InvalidData, "invalid voice target")));
+    }
+
+    Ok(target as u8)
+}
+
+/// Wire format used for the voice packets of a client, over UDP as well as through `UDPTunnel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceProtocol {
+    /// Bit-packed format used by every client before 1.5
+    Legacy,
+    /// `MumbleUDP` protobuf format, introduced in 1.5, only supports Opus
+    Protobuf,
+}
+
+impl VoiceProtocol {
+    /// Protobuf packets are only used when both sides announced at least version 1.5 in their
+    /// `Version` message, otherwise the client keeps using the legacy format.
+    pub fn negotiate(server_version: &Version, client_version: &Version) -> Self {
+        if get_version_v2(server_version) >= PROTOBUF_UDP_VERSION && get_version_v2(client_version) >= PROTOBUF_UDP_VERSION {
+            VoiceProtocol::Protobuf
+        } else {
+            VoiceProtocol::Legacy
+        }
+    }
+
+    /// Returns whether the packet can be represented in this format, the protobuf format has no
+    /// way to transmit CELT or Speex audio
+    pub fn supports<Dst: VoicePacketDst>(self, packet: &VoicePacket<Dst>) -> bool {
+        match (self, packet) {
+            (VoiceProtocol::Legacy, _) => true,
+            (VoiceProtocol::Protobuf, VoicePacket::Ping { .. }) => true,
+            (VoiceProtocol::Protobuf, VoicePacket::Audio { payload, .. }) => matches!(payload, VoicePacketPayload::Opus(_, _)),
+        }
+    }
+
+    pub fn decode<DecodeDst: VoicePacketDst>(self, buf: &mut BytesMut) -> Result<VoicePacket<DecodeDst>, DecryptError> {
+        match self {
+            VoiceProtocol::Legacy => decode_voice_packet(buf),
+            VoiceProtocol::Protobuf => decode_protobuf_voice_packet(buf),
+        }
+    }
+
+    pub fn encode<EncodeDst: VoicePacketDst>(self, item: &VoicePacket<EncodeDst>, dst: &mut BytesMut) -> Result<(), MumbleError> {
+        match self {
+            VoiceProtocol::Legacy => {
+                encode_voice_packet(item, dst);
+
+                Ok(())
+            }
+            VoiceProtocol::Protobuf => encode_protobuf_voice_packet(item, dst),
+        }
+    }
+}
+
+/// A client bound voice packet shared by all its recipients.
+///
+/// The packet is encoded at most once for each voice protocol, the first time a recipient using this
+/// protocol needs it, so sending it to many clients only costs one encryption per recipient.
+#[derive(Debug)]
+pub struct SharedVoicePacket {
+    pub packet: VoicePacket<ClientBound>,
+    legacy: OnceLock<Option<Bytes>>,
+    protobuf: OnceLock<Option<Bytes>>,
+}
+
+impl SharedVoicePacket {
+    pub fn new(packet: VoicePacket<ClientBound>) -> Self {
+        Self {
+            packet,
+            legacy: OnceLock::new(),
+            protobuf: OnceLock::new(),
+        }
+    }
+
+    /// Returns the packet encoded in the protocol, or `None` if the protocol can't represent it
+    pub fn encoded(&self, protocol: VoiceProtocol) -> Option<&Bytes> {
+        let encoded = match protocol {
+            VoiceProtocol::Legacy => &self.legacy,
+            VoiceProtocol::Protobuf => &self.protobuf,
+        };
+
+        encoded
+            .get_or_init(|| {
+                let mut dst = BytesMut::new();
+
+                match protocol.encode(&self.packet, &mut dst) {
+                    Ok(()) => Some(dst.freeze()),
+                    Err(_) => None,
+                }
+            })
+            .as_ref()
+    }
+}
+
+/// Reads the `[f32; 3]` position of the speaker from the positional audio information.
+///
+/// Positions are stored as little endian floats in the legacy format, which is also the
+/// representation used for positions of protobuf packets. Additional data is ignored.
+pub fn read_position(position_info: &[u8]) -> Option<[f32; 3]> {
+    if position_info.len() < 12 {
+        return None;
+    }
+
+    let mut buf = &position_info[..12];
+    let position = [buf.get_f32_le(), buf.get_f32_le(), buf.get_f32_le()];
+
+    if position.iter().all(|value| value.is_finite()) {
+        Some(position)
+    } else {
+        None
+    }
 }
 
 impl VoicePacket<ServerBound> {
@@ -120,6 +270,8 @@ impl VoicePacket<ServerBound> {
                 seq_num,
                 payload,
                 position_info,
+                // the volume is decided by the server, never by the speaker
+                volume_adjustment: None,
             },
         }
     }
@@ -138,32 +290,17 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
         let session_id = DecodeDst::read_session_id(&mut buf)?;
         let seq_num = buf.read_varint()?;
         let payload = match kind {
-            0 | 2 | 3 => {
-                let mut frames = Vec::new();
-                let position = buf.position();
-                buf_mut.advance(position as usize);
-                loop {
-                    if buf_mut.is_empty() {
-                        return Err(DecryptError::Eof);
-                    }
-                    let header = buf_mut[0];
-                    buf_mut.advance(1);
-
-                    let len = (header & !0x80) as usize;
-                    if buf_mut.len() < len {
-                        return Err(DecryptError::Eof);
-                    }
-                    frames.push(buf_mut.split_to(len).freeze());
-                    if header & 0x80 != 0x80 {
-                        break;
-                    }
-                }
-                match kind {
-                    0 => VoicePacketPayload::CeltAlpha(frames),
-                    2 => VoicePacketPayload::Speex(frames),
-                    3 => VoicePacketPayload::CeltBeta(frames),
-                    _ => panic!(),
-                }
+            0 => {
+                buf_mut.advance(buf.position() as usize);
+                VoicePacketPayload::CeltAlpha(decode_frames(buf_mut)?)
+            }
+            2 => {
+                buf_mut.advance(buf.position() as usize);
+                VoicePacketPayload::Speex(decode_frames(buf_mut)?)
+            }
+            3 => {
+                buf_mut.advance(buf.position() as usize);
+                VoicePacketPayload::CeltBeta(decode_frames(buf_mut)?)
             }
             4 => {
                 let header = buf.read_varint()?;
@@ -192,11 +329,35 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
             seq_num,
             payload,
             position_info,
+            volume_adjustment: None,
         }
     };
     Ok(result)
 }
 
+/// Reads the frames of CELT and Speex payloads, each one is prefixed by its length and a
+/// continuation bit telling whether another frame follows
+fn decode_frames(buf_mut: &mut BytesMut) -> Result<Vec<Bytes>, DecryptError> {
+    let mut frames = Vec::new();
+
+    loop {
+        if buf_mut.is_empty() {
+            return Err(DecryptError::Eof);
+        }
+        let header = buf_mut[0];
+        buf_mut.advance(1);
+
+        let len = (header & !0x80) as usize;
+        if buf_mut.len() < len {
+            return Err(DecryptError::Eof);
+        }
+        frames.push(buf_mut.split_to(len).freeze());
+        if header & 0x80 != 0x80 {
+            return Ok(frames);
+        }
+    }
+}
+
 pub fn encode_voice_packet<EncodeDst: VoicePacketDst>(item: &VoicePacket<EncodeDst>, dst: &mut BytesMut) {
     match item {
         VoicePacket::Ping { timestamp } => {
@@ -211,6 +372,7 @@ pub fn encode_voice_packet<EncodeDst: VoicePacketDst>(item: &VoicePacket<EncodeD
             seq_num,
             payload,
             position_info,
+            ..
         } => {
             let kind = match payload {
                 VoicePacketPayload::CeltAlpha(_) => 0,
@@ -245,3 +407,466 @@ pub fn encode_voice_packet<EncodeDst: VoicePacketDst>(item: &VoicePacket<EncodeD
         }
     }
 }
+
+/// Header byte of protobuf voice packets containing a `MumbleUDP.Audio` message
+pub const PROTOBUF_AUDIO: u8 = 0;
+/// Header byte of protobuf voice packets containing a `MumbleUDP.Ping` message
+pub const PROTOBUF_PING: u8 = 1;
+
+pub fn decode_protobuf_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) -> Result<VoicePacket<DecodeDst>, DecryptError> {
+    if buf_mut.is_empty() {
+        return Err(DecryptError::Eof);
+    }
+
+    let kind = buf_mut[0];
+    let data = buf_mut.split().split_off(1);
+
+    match kind {
+        PROTOBUF_AUDIO => {
+            check_field_lengths(&data)?;
+
+            let mut audio = mumble_udp::Audio::parse_from_bytes(&data)?;
+            let (target, session_id) = DecodeDst::read_protobuf_header(&audio)?;
+            let positional_data = audio.get_positional_data();
+
+            // keep the same representation as the legacy format so packets can be forwarded
+            // between clients using different formats
+            let position_info = if positional_data.is_empty() {
+                None
+            } else {
+                let mut position = BytesMut::with_capacity(positional_data.len() * 4);
+                for value in positional_data {
+                    position.put_f32_le(*value);
+                }
+
+                Some(position.freeze())
+            };
+
+            Ok(VoicePacket::Audio {
+                _dst: PhantomData,
+                target,
+                session_id,
+                seq_num: audio.get_frame_number(),
+                payload: VoicePacketPayload::Opus(Bytes::from(audio.take_opus_data()), audio.get_is_terminator()),
+                position_info,
+                // a value of 0 means that the field is unset
+                volume_adjustment: Some(audio.get_volume_adjustment()).filter(|volume| *volume != 0.0),
+            })
+        }
+        PROTOBUF_PING => {
+            let ping = mumble_udp::Ping::parse_from_bytes(&data)?;
+
+            Ok(VoicePacket::Ping {
+                timestamp: ping.get_timestamp(),
+            })
+        }
+        _ => Err(DecryptError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown voice packet type",
+        ))),
+    }
+}
+
+/// Checks that the length delimited fields of a message fit in it.
+///
+/// rust-protobuf reserves the memory of packed repeated floats (`positional_data`) from their
+/// declared length before comparing it to the size of the message, so a packet claiming a huge
+/// length would abort the server on the allocation.
+fn check_field_lengths(data: &[u8]) -> Result<(), DecryptError> {
+    let mut stream = CodedInputStream::from_bytes(data);
+
+    while !stream.eof()? {
+        let (_, wire_type) = stream.read_tag_unpack()?;
+
+        if wire_type != WireType::WireTypeLengthDelimited {
+            stream.skip_field(wire_type)?;
+            continue;
+        }
+
+        let len = stream.read_raw_varint64()?;
+        if len > data.len() as u64 - stream.pos() {
+            return Err(DecryptError::Eof);
+        }
+
+        stream.skip_raw_bytes(len as u32)?;
+    }
+
+    Ok(())
+}
+
+pub fn encode_protobuf_voice_packet<EncodeDst: VoicePacketDst>(
+    item: &VoicePacket<EncodeDst>,
+    dst: &mut BytesMut,
+) -> Result<(), MumbleError> {
+    match item {
+        VoicePacket::Ping { timestamp } => {
+            let mut ping = mumble_udp::Ping::new();
+            ping.set_timestamp(*timestamp);
+
+            dst.put_u8(PROTOBUF_PING);
+            dst.put_slice(&ping.write_to_bytes()?);
+        }
+        VoicePacket::Audio {
+            _dst,
+            target,
+            session_id,
+            seq_num,
+            payload,
+            position_info,
+            volume_adjustment,
+        } => {
+            let (frame, termination_bit) = match payload {
+                VoicePacketPayload::Opus(frame, termination_bit) => (frame, *termination_bit),
+                _ => return Err(MumbleError::UnsupportedVoiceCodec),
+            };
+
+            let mut audio = mumble_udp::Audio::new();
+            EncodeDst::write_protobuf_header(&mut audio, *target, session_id);
+            audio.set_frame_number(*seq_num);
+            audio.set_opus_data(frame.to_vec());
+            audio.set_is_terminator(termination_bit);
+
+            if let Some(volume_adjustment) = volume_adjustment {
+                audio.set_volume_adjustment(*volume_adjustment);
+            }
+
+            // positional data which isn't made of floats can't be represented, so we drop it
+            if let Some(position) = position_info.as_ref().filter(|position| position.len() % 4 == 0) {
+                let mut position = position.clone();
+                let mut positional_data = Vec::with_capacity(position.len() / 4);
+                while position.has_remaining() {
+                    positional_data.push(position.get_f32_le());
+                }
+
+                audio.set_positional_data(positional_data);
+            }
+
+            dst.put_u8(PROTOBUF_AUDIO);
+            dst.put_slice(&audio.write_to_bytes()?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn decode<Dst: VoicePacketDst>(protocol: VoiceProtocol, bytes: &[u8]) -> Result<VoicePacket<Dst>, DecryptError> {
+        protocol.decode(&mut BytesMut::from(bytes))
+    }
+
+    fn encode<Dst: VoicePacketDst>(protocol: VoiceProtocol, packet: &VoicePacket<Dst>) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        protocol.encode(packet, &mut dst).unwrap();
+        dst.to_vec()
+    }
+
+    fn position(values: [f32; 3]) -> Bytes {
+        let mut position = BytesMut::new();
+        for value in values {
+            position.put_f32_le(value);
+        }
+        position.freeze()
+    }
+
+    // TODO: add datagrams captured from real Mumble 1.4 (legacy) and 1.5 (protobuf) clients, the
+    // examples below are assembled from the protocol documentation so they can share a misreading of
+    // the spec with the decoder
+
+    #[test]
+    fn legacy_ping_example() {
+        let bytes = [0x20, 0x92, 0x34];
+
+        assert_eq!(
+            decode::<ServerBound>(VoiceProtocol::Legacy, &bytes).unwrap(),
+            VoicePacket::Ping { timestamp: 0x1234 }
+        );
+        assert_eq!(
+            encode(VoiceProtocol::Legacy, &VoicePacket::<ClientBound>::Ping { timestamp: 0x1234 }),
+            bytes
+        );
+    }
+
+    #[test]
+    fn legacy_server_bound_opus_example() {
+        // hand assembled from the protocol documentation: opus frame of a client talking normally,
+        // with the terminator bit and its position
+        let mut bytes = vec![0x80, 0x2a, 0xa0, 0x03, 0xfc, 0xff, 0xfe];
+        bytes.extend_from_slice(&position([1.0, 2.0, -3.5]));
+
+        let packet = VoicePacket::<ServerBound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: (),
+            seq_num: 0x2a,
+            payload: VoicePacketPayload::Opus(Bytes::from_static(&[0xfc, 0xff, 0xfe]), true),
+            position_info: Some(position([1.0, 2.0, -3.5])),
+            volume_adjustment: None,
+        };
+
+        assert_eq!(decode::<ServerBound>(VoiceProtocol::Legacy, &bytes).unwrap(), packet);
+        assert_eq!(encode(VoiceProtocol::Legacy, &packet), bytes);
+        assert_eq!(read_position(&bytes[7..]), Some([1.0, 2.0, -3.5]));
+    }
+
+    #[test]
+    fn legacy_client_bound_speex_example() {
+        // two speex frames whispered by session 0x1234
+        let bytes = [0x42, 0x92, 0x34, 0x82, 0x01, 0x81, 0x02, 0x02, 0x01, 0x02, 0x00];
+
+        let packet = VoicePacket::<ClientBound>::Audio {
+            _dst: PhantomData,
+            target: 2,
+            session_id: 0x1234,
+            seq_num: 0x201,
+            payload: VoicePacketPayload::Speex(vec![Bytes::from_static(&[0x02]), Bytes::from_static(&[0x01, 0x02])]),
+            position_info: Some(Bytes::from_static(&[0x00])),
+            volume_adjustment: None,
+        };
+
+        assert_eq!(decode::<ClientBound>(VoiceProtocol::Legacy, &bytes).unwrap(), packet);
+        assert_eq!(encode(VoiceProtocol::Legacy, &packet), bytes);
+    }
+
+    #[test]
+    fn protobuf_client_bound_opus_example() {
+        // hand assembled from MumbleUDP.proto: every field in declaration order with packed
+        // positional data, our encoder uses a different layout so only the decoded packets are
+        // compared
+        let bytes = [
+            0x00, // audio
+            0x10, 0x01, // context
+            0x18, 0x07, // sender_session
+            0x20, 0x05, // frame_number
+            0x2a, 0x02, 0xaa, 0xbb, // opus_data
+            0x32, 0x04, 0x00, 0x00, 0x80, 0x3f, // positional_data
+            0x3d, 0x00, 0x00, 0x00, 0x3f, // volume_adjustment
+            0x80, 0x01, 0x01, // is_terminator
+        ];
+
+        let packet = VoicePacket::<ClientBound>::Audio {
+            _dst: PhantomData,
+            target: 1,
+            session_id: 7,
+            seq_num: 5,
+            payload: VoicePacketPayload::Opus(Bytes::from_static(&[0xaa, 0xbb]), true),
+            position_info: Some(Bytes::from_static(&[0x00, 0x00, 0x80, 0x3f])),
+            volume_adjustment: Some(0.5),
+        };
+
+        assert_eq!(decode::<ClientBound>(VoiceProtocol::Protobuf, &bytes).unwrap(), packet);
+        assert_eq!(
+            decode::<ClientBound>(VoiceProtocol::Protobuf, &encode(VoiceProtocol::Protobuf, &packet)).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn protobuf_ping_example() {
+        let bytes = [0x01, 0x08, 0xb4, 0x24];
+
+        assert_eq!(
+            decode::<ServerBound>(VoiceProtocol::Protobuf, &bytes).unwrap(),
+            VoicePacket::Ping { timestamp: 0x1234 }
+        );
+        assert_eq!(
+            encode(VoiceProtocol::Protobuf, &VoicePacket::<ServerBound>::Ping { timestamp: 0x1234 }),
+            bytes
+        );
+    }
+
+    #[test]
+    fn unknown_kinds_are_errors() {
+        for header in [0xa0, 0xc0, 0xe0] {
+            assert!(decode::<ServerBound>(VoiceProtocol::Legacy, &[header, 0x00, 0x00]).is_err());
+        }
+
+        assert!(decode::<ServerBound>(VoiceProtocol::Protobuf, &[0x02]).is_err());
+        assert!(decode::<ServerBound>(VoiceProtocol::Protobuf, &[]).is_err());
+        // protobuf targets are limited to the 5 bits of the legacy format
+        assert!(decode::<ServerBound>(VoiceProtocol::Protobuf, &[0x00, 0x08, 0x20]).is_err());
+    }
+
+    #[test]
+    fn protobuf_lengths_larger_than_the_packet_are_errors() {
+        // positional data claiming several gigabytes, found by fuzzing
+        let bytes = [
+            0x00, 0x08, 0x00, 0x20, 0x05, 0x2a, 0x02, 0xaa, 0xbb, 0x32, 0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x12,
+        ];
+
+        assert!(decode::<ServerBound>(VoiceProtocol::Protobuf, &bytes).is_err());
+        assert!(decode::<ServerBound>(VoiceProtocol::Protobuf, &[0x00, 0x2a, 0x05, 0xaa]).is_err());
+    }
+
+    #[test]
+    fn protobuf_rejects_legacy_codecs() {
+        let packet = VoicePacket::<ClientBound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 1,
+            seq_num: 0,
+            payload: VoicePacketPayload::CeltAlpha(vec![Bytes::from_static(&[0x01])]),
+            position_info: None,
+            volume_adjustment: None,
+        };
+
+        assert!(!VoiceProtocol::Protobuf.supports(&packet));
+        assert!(VoiceProtocol::Protobuf.encode(&packet, &mut BytesMut::new()).is_err());
+    }
+
+    // the length of legacy frames is 7 bits long and the one of opus frames 13 bits long
+    fn legacy_payload() -> impl Strategy<Value = VoicePacketPayload> {
+        let frames = || vec(vec(any::<u8>(), 0..=127).prop_map(Bytes::from), 1..4);
+
+        prop_oneof![
+            frames().prop_map(VoicePacketPayload::CeltAlpha),
+            frames().prop_map(VoicePacketPayload::CeltBeta),
+            frames().prop_map(VoicePacketPayload::Speex),
+            opus_payload(),
+        ]
+    }
+
+    fn opus_payload() -> impl Strategy<Value = VoicePacketPayload> {
+        (vec(any::<u8>(), 0..=0x1fff), any::<bool>())
+            .prop_map(|(frame, terminator)| VoicePacketPayload::Opus(Bytes::from(frame), terminator))
+    }
+
+    fn legacy_position() -> impl Strategy<Value = Option<Bytes>> {
+        proptest::option::of(vec(any::<u8>(), 1..32).prop_map(Bytes::from))
+    }
+
+    fn protobuf_position() -> impl Strategy<Value = Option<Bytes>> {
+        proptest::option::of(vec(-1e6f32..1e6, 1..4).prop_map(|values| {
+            let mut position = BytesMut::new();
+            for value in values {
+                position.put_f32_le(value);
+            }
+            position.freeze()
+        }))
+    }
+
+    fn assert_round_trip<Dst: VoicePacketDst + Debug>(protocol: VoiceProtocol, packet: VoicePacket<Dst>) -> Result<(), TestCaseError> {
+        let bytes = encode(protocol, &packet);
+
+        prop_assert_eq!(decode::<Dst>(protocol, &bytes).unwrap(), packet);
+
+        // every truncation of the packet must be an error or decode without panicking, the
+        // position info and the protobuf fields are optional so shorter packets can be valid
+        for len in 0..bytes.len() {
+            let _ = decode::<Dst>(protocol, &bytes[..len]);
+        }
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn legacy_ping_round_trip(timestamp: u64) {
+            assert_round_trip(VoiceProtocol::Legacy, VoicePacket::<ServerBound>::Ping { timestamp })?;
+            assert_round_trip(VoiceProtocol::Legacy, VoicePacket::<ClientBound>::Ping { timestamp })?;
+        }
+
+        #[test]
+        fn protobuf_ping_round_trip(timestamp: u64) {
+            assert_round_trip(VoiceProtocol::Protobuf, VoicePacket::<ServerBound>::Ping { timestamp })?;
+            assert_round_trip(VoiceProtocol::Protobuf, VoicePacket::<ClientBound>::Ping { timestamp })?;
+        }
+
+        #[test]
+        fn legacy_server_bound_round_trip(target in 0u8..32, seq_num: u64, payload in legacy_payload(), position_info in legacy_position()) {
+            assert_round_trip(VoiceProtocol::Legacy, VoicePacket::<ServerBound>::Audio {
+                _dst: PhantomData,
+                target,
+                session_id: (),
+                seq_num,
+                payload,
+                position_info,
+                volume_adjustment: None,
+            })?;
+        }
+
+        #[test]
+        fn legacy_client_bound_round_trip(
+            target in 0u8..32,
+            session_id: u32,
+            seq_num: u64,
+            payload in legacy_payload(),
+            position_info in legacy_position(),
+        ) {
+            assert_round_trip(VoiceProtocol::Legacy, VoicePacket::<ClientBound>::Audio {
+                _dst: PhantomData,
+                target,
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+                volume_adjustment: None,
+            })?;
+        }
+
+        #[test]
+        fn protobuf_server_bound_round_trip(target in 0u8..32, seq_num: u64, payload in opus_payload(), position_info in protobuf_position()) {
+            assert_round_trip(VoiceProtocol::Protobuf, VoicePacket::<ServerBound>::Audio {
+                _dst: PhantomData,
+                target,
+                session_id: (),
+                seq_num,
+                payload,
+                position_info,
+                volume_adjustment: None,
+            })?;
+        }
+
+        #[test]
+        fn protobuf_client_bound_round_trip(
+            target in 0u8..32,
+            session_id: u32,
+            seq_num: u64,
+            payload in opus_payload(),
+            position_info in protobuf_position(),
+            volume_adjustment in proptest::option::of(0.01f32..10.0),
+        ) {
+            assert_round_trip(VoiceProtocol::Protobuf, VoicePacket::<ClientBound>::Audio {
+                _dst: PhantomData,
+                target,
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+                volume_adjustment,
+            })?;
+        }
+
+        #[test]
+        fn truncated_legacy_audio_is_an_error(target in 0u8..32, session_id: u32, seq_num: u64, payload in legacy_payload()) {
+            // without position info, the payload is the end of the packet so any truncation is invalid
+            let packet = VoicePacket::<ClientBound>::Audio {
+                _dst: PhantomData,
+                target,
+                session_id,
+                seq_num,
+                payload,
+                position_info: None,
+                volume_adjustment: None,
+            };
+            let bytes = encode(VoiceProtocol::Legacy, &packet);
+
+            for len in 0..bytes.len() {
+                let truncated = decode::<ClientBound>(VoiceProtocol::Legacy, &bytes[..len]);
+                prop_assert!(truncated.is_err(), "{:02x?} decoded as {:?}", &bytes[..len], truncated);
+            }
+        }
+
+        #[test]
+        fn arbitrary_input_never_panics(bytes in vec(any::<u8>(), 0..256)) {
+            let _ = decode::<ServerBound>(VoiceProtocol::Legacy, &bytes);
+            let _ = decode::<ClientBound>(VoiceProtocol::Legacy, &bytes);
+            let _ = decode::<ServerBound>(VoiceProtocol::Protobuf, &bytes);
+            let _ = decode::<ClientBound>(VoiceProtocol::Protobuf, &bytes);
+        }
+    }
+}